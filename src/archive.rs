@@ -0,0 +1,697 @@
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Read},
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+};
+
+use flate2::read::GzDecoder;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::fixture::{ArchiveFormat, ArchiveSetup};
+
+/// Where downloaded archives are kept, by checksum
+pub fn cache_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap()
+        .join("spaceconf")
+        .join("archives")
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+struct Extracted {
+    sha256: String,
+    files: Vec<PathBuf>,
+}
+
+/// The files extracted into each destination, so an archive that is already extracted isn't
+/// fetched again and can be removed on revert
+pub struct ArchiveState {
+    path: PathBuf,
+    extracted: BTreeMap<PathBuf, Extracted>,
+}
+
+impl ArchiveState {
+    pub fn state_file() -> PathBuf {
+        dirs::state_dir()
+            .unwrap()
+            .join("spaceconf")
+            .join("archives.json")
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let extracted = if path.exists() {
+            let content = std::fs::read_to_string(path)
+                .inspect_err(|_| error!("failed to read {}", &path.to_string_lossy()))?;
+            serde_json::from_str(&content)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            extracted,
+        })
+    }
+
+    /// Whether this archive was extracted into the destination and all of its files are still
+    /// there
+    pub fn is_extracted(&self, dest: &Path, sha256: &str) -> bool {
+        self.extracted.get(dest).is_some_and(|extracted| {
+            extracted.sha256.eq_ignore_ascii_case(sha256)
+                && extracted.files.iter().all(|file| dest.join(file).exists())
+        })
+    }
+
+    /// Paths of the files extracted into the destination
+    pub fn files(&self, dest: &Path) -> Vec<PathBuf> {
+        self.extracted
+            .get(dest)
+            .map(|extracted| extracted.files.iter().map(|file| dest.join(file)).collect())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        std::fs::create_dir_all(self.path.parent().unwrap()).inspect_err(|_| {
+            error!(
+                "failed to create parent directory(s): {}",
+                &self.path.to_string_lossy()
+            )
+        })?;
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.extracted)?)
+            .inspect_err(|_| error!("failed to write {}", &self.path.to_string_lossy()))
+    }
+}
+
+/// Fetch the archive, verify it and extract it into `dest`, unless it already is
+pub fn apply(
+    setup: &ArchiveSetup,
+    dest: &Path,
+    cache_dir: &Path,
+    state: &mut ArchiveState,
+) -> std::io::Result<()> {
+    if state.is_extracted(dest, &setup.sha256) {
+        println!("{} is up to date", dest.to_string_lossy());
+        return Ok(());
+    }
+
+    let data = fetch(setup, cache_dir)?;
+    let previous = state
+        .extracted
+        .remove(dest)
+        .map(|extracted| extracted.files)
+        .unwrap_or_default();
+    let mut files = Vec::new();
+    if let Err(e) = extract(setup, &data, dest, &mut files) {
+        // recorded so revert can remove them, without a checksum so the next apply extracts again
+        let mut extracted: Vec<_> = previous
+            .into_iter()
+            .filter(|file| !files.contains(file))
+            .collect();
+        extracted.extend(files);
+        state.extracted.insert(
+            dest.to_path_buf(),
+            Extracted {
+                sha256: String::new(),
+                files: extracted,
+            },
+        );
+        state.save()?;
+        return Err(e);
+    }
+    println!(
+        "Extracted {} file(s) from {} into {:?}",
+        files.len(),
+        setup.url,
+        dest
+    );
+
+    // left over from an earlier version of the archive
+    let stale: Vec<_> = previous
+        .into_iter()
+        .filter(|file| !files.contains(file))
+        .collect();
+    if !stale.is_empty() {
+        remove_files(dest, &stale)?;
+        println!("Removed {} stale file(s) from {:?}", stale.len(), dest);
+    }
+
+    state.extracted.insert(
+        dest.to_path_buf(),
+        Extracted {
+            sha256: setup.sha256.to_lowercase(),
+            files,
+        },
+    );
+    state.save()
+}
+
+/// Remove the files that were extracted into `dest`
+pub fn revert(dest: &Path, state: &mut ArchiveState) -> std::io::Result<()> {
+    let Some(extracted) = state.extracted.remove(dest) else {
+        return Ok(());
+    };
+
+    remove_files(dest, &extracted.files)?;
+    println!("Removed {} file(s) from {:?}", extracted.files.len(), dest);
+
+    state.save()
+}
+
+fn remove_files(dest: &Path, files: &[PathBuf]) -> std::io::Result<()> {
+    for file in files {
+        let path = dest.join(file);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                error!("failed to remove: {}", &path.to_string_lossy());
+                return Err(e);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the archive from the cache, or download it and cache it, checking it against its
+/// checksum
+fn fetch(setup: &ArchiveSetup, cache_dir: &Path) -> std::io::Result<Vec<u8>> {
+    let expected = setup.sha256.to_lowercase();
+    let cached = cache_dir.join(&expected);
+    if let Ok(data) = std::fs::read(&cached) {
+        if sha256(&data) == expected {
+            return Ok(data);
+        }
+    }
+
+    println!("Downloading {}", setup.url);
+    let data = download(&setup.url)?;
+    let actual = sha256(&data);
+    if actual != expected {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "checksum mismatch for {}: expected {}, got {}",
+                setup.url, expected, actual
+            ),
+        ));
+    }
+
+    std::fs::create_dir_all(cache_dir).inspect_err(|_| {
+        error!(
+            "failed to create cache directory: {}",
+            &cache_dir.to_string_lossy()
+        )
+    })?;
+    // renamed into place so an interrupted write never looks like a cached archive
+    let partial = cache_dir.join(format!("{}.partial", expected));
+    std::fs::write(&partial, &data)?;
+    std::fs::rename(&partial, &cached)?;
+
+    Ok(data)
+}
+
+fn download(url: &str) -> std::io::Result<Vec<u8>> {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        let path = url.strip_prefix("file://").unwrap_or(url);
+        return std::fs::read(path).inspect_err(|_| error!("failed to read archive: {}", path));
+    }
+
+    let response = ureq::get(url)
+        .call()
+        .map_err(|e| std::io::Error::other(format!("failed to download {}: {}", url, e)))?;
+    let mut data = Vec::new();
+    response.into_reader().read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Which entries are extracted, and where
+struct Filter {
+    strip_components: usize,
+    include: Option<GlobSet>,
+}
+
+impl Filter {
+    fn new(setup: &ArchiveSetup) -> std::io::Result<Self> {
+        let include = if setup.include.is_empty() {
+            None
+        } else {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in &setup.include {
+                builder.add(Glob::new(pattern).map_err(std::io::Error::other)?);
+            }
+            Some(builder.build().map_err(std::io::Error::other)?)
+        };
+
+        Ok(Self {
+            strip_components: setup.strip_components,
+            include,
+        })
+    }
+
+    /// Path of an entry inside the destination, or `None` if it isn't extracted. Entries that
+    /// would end up outside the destination are an error.
+    fn path(&self, entry: &Path) -> std::io::Result<Option<PathBuf>> {
+        let mut components = Vec::new();
+        for component in entry.components() {
+            match component {
+                Component::Normal(component) => components.push(component),
+                Component::CurDir => {}
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("unsafe path in archive: {}", entry.display()),
+                    ))
+                }
+            }
+        }
+
+        if components.len() <= self.strip_components {
+            return Ok(None);
+        }
+        let path: PathBuf = components[self.strip_components..].iter().collect();
+        if self
+            .include
+            .as_ref()
+            .is_some_and(|include| !include.is_match(&path))
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(path))
+    }
+}
+
+/// Extract the archive's files into `dest`, adding their paths relative to it to `files` as
+/// they are extracted, so they are known even if extracting fails part-way
+fn extract(
+    setup: &ArchiveSetup,
+    data: &[u8],
+    dest: &Path,
+    files: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    let filter = Filter::new(setup)?;
+    match ArchiveFormat::detect(&setup.url) {
+        Some(ArchiveFormat::TarGz) => extract_tar_gz(data, dest, &filter, files),
+        Some(ArchiveFormat::Zip) => extract_zip(data, dest, &filter, files),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("cannot detect the archive format of {}", setup.url),
+        )),
+    }
+}
+
+fn extract_tar_gz(
+    data: &[u8],
+    dest: &Path,
+    filter: &Filter,
+    files: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    let mut archive = tar::Archive::new(GzDecoder::new(data));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        let Some(path) = filter.path(&entry.path()?)? else {
+            continue;
+        };
+
+        let target = dest.join(&path);
+        prepare_target(dest, &path)?;
+        match entry.header().entry_type() {
+            tar::EntryType::Symlink => {
+                let link = entry.link_name()?.unwrap_or_default().into_owned();
+                if !link_stays_inside(&path, &link) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "symlink in archive points outside the destination: {} -> {}",
+                            path.display(),
+                            link.display()
+                        ),
+                    ));
+                }
+                std::os::unix::fs::symlink(&link, &target)
+                    .inspect_err(|_| error!("failed to extract: {}", &target.to_string_lossy()))?;
+            }
+            // hard link targets are archive paths, so they are stripped like entry paths and
+            // have to be one of the files already extracted
+            tar::EntryType::Link => {
+                let link = entry.link_name()?.unwrap_or_default().into_owned();
+                let Some(original) = filter.path(&link)?.filter(|link| files.contains(link)) else {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "hard link in archive points to a file that isn't extracted: {} -> {}",
+                            path.display(),
+                            link.display()
+                        ),
+                    ));
+                };
+                std::fs::hard_link(dest.join(original), &target)
+                    .inspect_err(|_| error!("failed to extract: {}", &target.to_string_lossy()))?;
+            }
+            _ => {
+                entry
+                    .unpack(&target)
+                    .inspect_err(|_| error!("failed to extract: {}", &target.to_string_lossy()))?;
+            }
+        }
+        files.push(path);
+    }
+
+    Ok(())
+}
+
+fn extract_zip(
+    data: &[u8],
+    dest: &Path,
+    filter: &Filter,
+    files: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(std::io::Error::other)?;
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index).map_err(std::io::Error::other)?;
+        if file.is_dir() {
+            continue;
+        }
+        let Some(name) = file.enclosed_name() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unsafe path in archive: {}", file.name()),
+            ));
+        };
+        let Some(path) = filter.path(&name)? else {
+            continue;
+        };
+
+        let target = dest.join(&path);
+        prepare_target(dest, &path)?;
+        let mut out = std::fs::File::create(&target)
+            .inspect_err(|_| error!("failed to extract: {}", &target.to_string_lossy()))?;
+        std::io::copy(&mut file, &mut out)?;
+        if let Some(mode) = file.unix_mode() {
+            out.set_permissions(std::fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+        files.push(path);
+    }
+
+    Ok(())
+}
+
+/// Create the parent directories of an entry, refusing to write through a symlink, which could
+/// lead outside the destination. An existing symlink at the entry itself is removed so it gets
+/// replaced rather than followed.
+fn prepare_target(dest: &Path, path: &Path) -> std::io::Result<()> {
+    let target = dest.join(path);
+    std::fs::create_dir_all(target.parent().unwrap()).inspect_err(|_| {
+        error!(
+            "failed to create parent directory(s): {}",
+            &target.to_string_lossy()
+        )
+    })?;
+
+    let mut parent = dest.to_path_buf();
+    for component in path.parent().into_iter().flat_map(Path::components) {
+        parent.push(component);
+        if parent.symlink_metadata()?.is_symlink() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "archive entry would be extracted through a symlink: {}",
+                    path.display()
+                ),
+            ));
+        }
+    }
+
+    if target
+        .symlink_metadata()
+        .is_ok_and(|meta| meta.is_symlink())
+    {
+        std::fs::remove_file(&target)
+            .inspect_err(|_| error!("failed to replace symlink: {}", &target.to_string_lossy()))?;
+    }
+
+    Ok(())
+}
+
+/// Whether a symlink at `path` inside the destination points to somewhere inside it too
+fn link_stays_inside(path: &Path, link: &Path) -> bool {
+    let mut depth = path.components().count() - 1;
+    for component in link.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    fn tar_gz(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (path, content) in files {
+            writer
+                .start_file(*path, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn setup(path: &Path, data: &[u8]) -> ArchiveSetup {
+        std::fs::write(path, data).unwrap();
+        ArchiveSetup {
+            url: format!("file://{}", path.display()),
+            sha256: sha256(data),
+            dest: PathBuf::new(),
+            strip_components: 1,
+            include: vec!["*.ttf".into()],
+        }
+    }
+
+    #[test]
+    fn test_apply_tar_gz() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let data = tar_gz(&[
+            ("FiraCode/README.md", "readme"),
+            ("FiraCode/ttf/FiraCode-Regular.ttf", "regular"),
+        ]);
+        let setup = setup(&test_dir.path().join("FiraCode.tar.gz"), &data);
+        let dest = test_dir.path().join("fonts");
+        let cache = test_dir.path().join("cache");
+        let mut state = ArchiveState::load(&test_dir.path().join("archives.json")).unwrap();
+
+        apply(&setup, &dest, &cache, &mut state).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest.join("ttf/FiraCode-Regular.ttf")).unwrap(),
+            "regular"
+        );
+        assert!(!dest.join("README.md").exists());
+        assert!(cache.join(&setup.sha256).exists());
+
+        // already extracted, so neither the source nor the cache is needed
+        std::fs::remove_file(test_dir.path().join("FiraCode.tar.gz")).unwrap();
+        std::fs::remove_dir_all(&cache).unwrap();
+        let mut state = ArchiveState::load(&test_dir.path().join("archives.json")).unwrap();
+        apply(&setup, &dest, &cache, &mut state).unwrap();
+
+        revert(&dest, &mut state).unwrap();
+        assert!(!dest.join("ttf/FiraCode-Regular.ttf").exists());
+    }
+
+    #[test]
+    fn test_apply_zip() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let data = zip(&[("Nord/colors.txt", "colors"), ("Nord/Nord.ttf", "font")]);
+        let setup = setup(&test_dir.path().join("Nord.zip"), &data);
+        let dest = test_dir.path().join("fonts");
+        let mut state = ArchiveState::load(&test_dir.path().join("archives.json")).unwrap();
+
+        apply(&setup, &dest, &test_dir.path().join("cache"), &mut state).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest.join("Nord.ttf")).unwrap(),
+            "font"
+        );
+        assert!(!dest.join("colors.txt").exists());
+    }
+
+    #[test]
+    fn test_apply_new_version() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let dest = test_dir.path().join("fonts");
+        let cache = test_dir.path().join("cache");
+        let mut state = ArchiveState::load(&test_dir.path().join("archives.json")).unwrap();
+
+        let old = setup(
+            &test_dir.path().join("old.tar.gz"),
+            &tar_gz(&[("a/Regular.ttf", "regular"), ("a/Retina.ttf", "retina")]),
+        );
+        apply(&old, &dest, &cache, &mut state).unwrap();
+        assert!(dest.join("Retina.ttf").exists());
+
+        let new = setup(
+            &test_dir.path().join("new.tar.gz"),
+            &tar_gz(&[("a/Regular.ttf", "regular v2")]),
+        );
+        apply(&new, &dest, &cache, &mut state).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest.join("Regular.ttf")).unwrap(),
+            "regular v2"
+        );
+        assert!(!dest.join("Retina.ttf").exists());
+        assert_eq!(state.files(&dest), vec![dest.join("Regular.ttf")]);
+    }
+
+    #[test]
+    fn test_apply_fails_part_way() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "a/Good.ttf", "good".as_bytes())
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder
+            .append_link(&mut header, "a/Evil.ttf", "../../outside")
+            .unwrap();
+        let data = builder.into_inner().unwrap().finish().unwrap();
+
+        let setup = setup(&test_dir.path().join("font.tar.gz"), &data);
+        let dest = test_dir.path().join("fonts");
+        let cache = test_dir.path().join("cache");
+        let mut state = ArchiveState::load(&test_dir.path().join("archives.json")).unwrap();
+
+        assert!(apply(&setup, &dest, &cache, &mut state).is_err());
+        assert!(dest.join("Good.ttf").exists());
+        // not mistaken for a complete extraction, but cleaned up by revert
+        assert!(!state.is_extracted(&dest, &setup.sha256));
+        let mut state = ArchiveState::load(&test_dir.path().join("archives.json")).unwrap();
+        revert(&dest, &mut state).unwrap();
+        assert!(!dest.join("Good.ttf").exists());
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let mut setup = setup(
+            &test_dir.path().join("font.tar.gz"),
+            &tar_gz(&[("a/font.ttf", "font")]),
+        );
+        setup.sha256 = sha256(b"something else");
+        let dest = test_dir.path().join("fonts");
+        let cache = test_dir.path().join("cache");
+        let mut state = ArchiveState::load(&test_dir.path().join("archives.json")).unwrap();
+
+        let error = apply(&setup, &dest, &cache, &mut state).unwrap_err();
+        assert!(error.to_string().contains("checksum mismatch"));
+        assert!(!dest.exists());
+        assert!(!cache.join(&setup.sha256).exists());
+    }
+
+    #[test]
+    fn test_symlink_outside_dest() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let outside = test_dir.path().join("outside");
+        std::fs::create_dir(&outside).unwrap();
+
+        for link in [outside.to_str().unwrap(), "../../outside"] {
+            let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            header.set_mode(0o777);
+            builder.append_link(&mut header, "top/link", link).unwrap();
+            let mut header = tar::Header::new_gnu();
+            header.set_size(5);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, "top/link/pwned", "pwned".as_bytes())
+                .unwrap();
+            let data = builder.into_inner().unwrap().finish().unwrap();
+
+            let mut setup = setup(&test_dir.path().join("evil.tar.gz"), &data);
+            setup.strip_components = 0;
+            setup.include = vec![];
+            let dest = test_dir.path().join("dest");
+            let mut state = ArchiveState::load(&test_dir.path().join("archives.json")).unwrap();
+
+            assert!(apply(&setup, &dest, &test_dir.path().join("cache"), &mut state).is_err());
+            assert!(!outside.join("pwned").exists());
+        }
+    }
+
+    #[test]
+    fn test_link_stays_inside() {
+        assert!(link_stays_inside(
+            Path::new("bin/tool"),
+            Path::new("../lib/tool")
+        ));
+        assert!(!link_stays_inside(
+            Path::new("bin/tool"),
+            Path::new("../../etc")
+        ));
+        assert!(!link_stays_inside(
+            Path::new("tool"),
+            Path::new("/etc/passwd")
+        ));
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter {
+            strip_components: 1,
+            include: None,
+        };
+        assert_eq!(filter.path(Path::new("top")).unwrap(), None);
+        assert_eq!(
+            filter.path(Path::new("./top/bin/tool")).unwrap(),
+            Some(PathBuf::from("bin/tool"))
+        );
+        assert!(filter.path(Path::new("top/../../etc/passwd")).is_err());
+    }
+}
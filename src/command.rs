@@ -0,0 +1,254 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use log::error;
+
+use crate::{fixture::CommandSetup, secrets, template::Renderer};
+
+/// Commands with `run_once` that already ran on this machine, by fixture name. The command is
+/// recorded as it ran, so changing it makes it run again.
+pub struct RunOnce {
+    path: PathBuf,
+    ran: BTreeMap<String, String>,
+}
+
+impl RunOnce {
+    pub fn state_file() -> PathBuf {
+        dirs::state_dir()
+            .unwrap()
+            .join("spaceconf")
+            .join("run-once.json")
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let ran = if path.exists() {
+            let content = std::fs::read_to_string(path)
+                .inspect_err(|_| error!("failed to read {}", &path.to_string_lossy()))?;
+            serde_json::from_str(&content)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            ran,
+        })
+    }
+
+    fn has_run(&self, fixture: &str, command: &str) -> bool {
+        self.ran.get(fixture).is_some_and(|ran| ran == command)
+    }
+
+    fn record(&mut self, fixture: &str, command: &str) -> std::io::Result<()> {
+        self.ran.insert(fixture.to_string(), command.to_string());
+
+        std::fs::create_dir_all(self.path.parent().unwrap()).inspect_err(|_| {
+            error!(
+                "failed to create parent directory(s): {}",
+                &self.path.to_string_lossy()
+            )
+        })?;
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.ran)?)
+            .inspect_err(|_| error!("failed to write {}", &self.path.to_string_lossy()))
+    }
+}
+
+/// A command fixture with its templates rendered
+pub struct Rendered {
+    pub command: String,
+    creates: Option<PathBuf>,
+    unless: Option<String>,
+    only_if: Option<String>,
+    run_once: bool,
+}
+
+pub fn render(setup: &CommandSetup, renderer: &Renderer) -> std::io::Result<Rendered> {
+    let render = |source: &str| {
+        renderer
+            .render_file(Path::new("command"), source)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    };
+
+    Ok(Rendered {
+        command: render(&setup.command)?,
+        creates: setup
+            .creates
+            .as_deref()
+            .map(|creates| renderer.render_path(creates))
+            .transpose()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+        unless: setup.unless.as_deref().map(render).transpose()?,
+        only_if: setup.only_if.as_deref().map(render).transpose()?,
+        run_once: setup.run_once,
+    })
+}
+
+/// Why the command doesn't need to run, if any of its guards says so
+pub fn skip_reason(
+    fixture: &str,
+    command: &Rendered,
+    run_once: &RunOnce,
+) -> std::io::Result<Option<String>> {
+    if command.run_once && run_once.has_run(fixture, &command.command) {
+        return Ok(Some("already ran".to_string()));
+    }
+    if let Some(creates) = &command.creates {
+        if creates.exists() {
+            return Ok(Some(format!("{} exists", creates.to_string_lossy())));
+        }
+    }
+    if let Some(unless) = &command.unless {
+        if check(unless)? {
+            return Ok(Some(format!("`{}` succeeded", unless)));
+        }
+    }
+    if let Some(only_if) = &command.only_if {
+        if !check(only_if)? {
+            return Ok(Some(format!("`{}` failed", only_if)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Run the command unless a guard skips it, printing its output. A failure includes the output.
+pub fn run(fixture: &str, command: &Rendered, run_once: &mut RunOnce) -> std::io::Result<()> {
+    if let Some(reason) = skip_reason(fixture, command, run_once)? {
+        println!("Skipping {}: {}", fixture, reason);
+        return Ok(());
+    }
+
+    println!("Running {}", fixture);
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(&command.command)
+        .stdin(Stdio::inherit())
+        .output()
+        .inspect_err(|_| error!("failed to run command for {}", fixture))?;
+
+    let mut captured = String::from_utf8_lossy(&output.stdout).to_string();
+    captured += &String::from_utf8_lossy(&output.stderr);
+    let captured = secrets::redact(captured.trim_end());
+
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "command exited with {}\n{}",
+            output.status, captured
+        )));
+    }
+
+    for line in captured.lines() {
+        println!("    {}", line);
+    }
+    if command.run_once {
+        run_once.record(fixture, &command.command)?;
+    }
+
+    Ok(())
+}
+
+/// Run a guard, returning whether it succeeded
+fn check(check: &str) -> std::io::Result<bool> {
+    Command::new("sh")
+        .arg("-c")
+        .arg(check)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .inspect_err(|_| error!("failed to run check `{}`", check))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{host::Host, vars::Vars};
+
+    fn setup(command: &str) -> CommandSetup {
+        CommandSetup {
+            command: command.to_string(),
+            creates: None,
+            unless: None,
+            only_if: None,
+            run_once: false,
+        }
+    }
+
+    fn rendered(setup: &CommandSetup) -> Rendered {
+        render(setup, &Renderer::new(&Host::current(), &Vars::new(), false)).unwrap()
+    }
+
+    #[test]
+    fn test_guards() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let run_once = RunOnce::load(&test_dir.path().join("run-once.json")).unwrap();
+
+        let command = rendered(&setup("true"));
+        assert_eq!(skip_reason("test", &command, &run_once).unwrap(), None);
+
+        let creates = CommandSetup {
+            creates: Some(test_dir.path().to_path_buf()),
+            ..setup("true")
+        };
+        assert!(skip_reason("test", &rendered(&creates), &run_once)
+            .unwrap()
+            .is_some());
+
+        let unless = CommandSetup {
+            unless: Some("true".into()),
+            ..setup("true")
+        };
+        assert_eq!(
+            skip_reason("test", &rendered(&unless), &run_once).unwrap(),
+            Some("`true` succeeded".to_string())
+        );
+
+        let only_if = CommandSetup {
+            only_if: Some("false".into()),
+            ..setup("true")
+        };
+        assert_eq!(
+            skip_reason("test", &rendered(&only_if), &run_once).unwrap(),
+            Some("`false` failed".to_string())
+        );
+    }
+
+    #[test]
+    fn test_run_once() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let state = test_dir.path().join("state/run-once.json");
+        let marker = test_dir.path().join("ran");
+        let mut run_once = RunOnce::load(&state).unwrap();
+
+        let once = CommandSetup {
+            run_once: true,
+            ..setup(&format!("echo x >> '{}'", marker.display()))
+        };
+        let command = rendered(&once);
+        run("test", &command, &mut run_once).unwrap();
+
+        // the record survives a reload, so the command doesn't run again
+        let mut run_once = RunOnce::load(&state).unwrap();
+        run("test", &command, &mut run_once).unwrap();
+        assert_eq!(std::fs::read_to_string(&marker).unwrap(), "x\n");
+    }
+
+    #[test]
+    fn test_run_failure_includes_output() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let mut run_once = RunOnce::load(&test_dir.path().join("run-once.json")).unwrap();
+
+        let error = run(
+            "test",
+            &rendered(&setup("echo no such schema >&2; exit 1")),
+            &mut run_once,
+        )
+        .unwrap_err();
+        assert!(error.to_string().ends_with("\nno such schema"));
+    }
+}
@@ -1,11 +1,18 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     path::{Path, PathBuf},
 };
 
 use resolve_path::PathResolveExt;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    host::Host,
+    secrets::Secrets,
+    template::{self, Renderer},
+    vars::Vars,
+};
+
 type Specifier = String;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -15,27 +22,77 @@ pub struct Fixture {
     pub(crate) include_for: Option<Vec<Specifier>>,
     pub(crate) exclude_for: Option<Vec<Specifier>>,
 
+    #[serde(default, skip_serializing_if = "Vars::is_empty")]
+    pub vars: Vars,
+
+    /// Directory the fixture was loaded from
+    #[serde(skip)]
+    pub dir: PathBuf,
+
     #[serde(flatten)]
     pub fixture_type: FixtureType,
 }
 
 impl Fixture {
+    /// The dotfiles repository containing the fixture
+    pub fn repo_dir(&self) -> Option<&Path> {
+        self.dir.parent()
+    }
+
     pub fn validate(&self) -> Result<(), String> {
+        self.validate_for(&Host::current())
+    }
+
+    pub fn validate_for(&self, host: &Host) -> Result<(), String> {
         match &self.fixture_type {
             FixtureType::Files(files) => {
                 if files.files.is_empty() {
                     return Err("Files fixture must have at least one file".to_string());
                 }
 
+                let renderer = Renderer::new(host, &self.vars, files.allow_commands)
+                    .with_secrets(&files.secrets);
+
+                // blocks are marked with the fixture name, so each needs its own destination
+                let mut blocks = Vec::new();
                 for file in &files.files {
-                    if file.src.clone().resolve().is_none() && !file.optional {
+                    if let Some(dest) = file.dest.clone().select_for(host) {
+                        let dest = renderer.render_path(&dest).map_err(|e| {
+                            format!(
+                                "Destination {:?} cannot be resolved: {}",
+                                dest,
+                                template::error_chain(&e)
+                            )
+                        })?;
+                        if file.strategy == Strategy::Block {
+                            if blocks.contains(&dest) {
+                                return Err(format!(
+                                    "Only one block can be kept in {:?} per fixture",
+                                    dest
+                                ));
+                            }
+                            blocks.push(dest);
+                        }
+                    }
+
+                    if file.purge && file.state != State::Directory {
+                        return Err("Only directories can be purged".to_string());
+                    }
+
+                    if file.state != State::Present {
+                        if !file.src.is_empty() {
+                            return Err(
+                                "Absent files and directories cannot have a source".to_string()
+                            );
+                        }
+                    } else if file.src.clone().resolve_for(host).is_none() && !file.optional {
                         return Err(
                             "Source file cannot be resolved and is not marked as optional"
                                 .to_string(),
                         );
                     }
 
-                    if file.dest.clone().resolve().is_none() && !file.optional {
+                    if file.dest.clone().resolve_for(host).is_none() && !file.optional {
                         return Err(
                             "Destination file cannot be resolved and is not marked as optional"
                                 .to_string(),
@@ -47,6 +104,86 @@ impl Fixture {
                 if repo.repository.is_empty() {
                     return Err("Repository fixture must have a repository URL".to_string());
                 }
+
+                Renderer::new(host, &self.vars, false)
+                    .render_path(&repo.path)
+                    .map_err(|e| {
+                        format!(
+                            "Repository path {:?} cannot be resolved: {}",
+                            repo.path,
+                            template::error_chain(&e)
+                        )
+                    })?;
+            }
+            FixtureType::Systemd(systemd) => {
+                if systemd.units.is_empty() {
+                    return Err("Systemd fixture must have at least one unit".to_string());
+                }
+
+                for unit in &systemd.units {
+                    if !unit.src.is_file() {
+                        return Err(format!("Unit file {:?} does not exist", unit.src));
+                    }
+                }
+            }
+            FixtureType::Packages(packages) => {
+                if packages.packages.is_empty() {
+                    return Err("Packages fixture must list at least one package".to_string());
+                }
+            }
+            FixtureType::Command(command) => {
+                if command.command.trim().is_empty() {
+                    return Err("Command fixture must have a command".to_string());
+                }
+            }
+            FixtureType::Archive(archive) => {
+                let sha256 = &archive.sha256;
+                if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!("Invalid sha256 for {}: {:?}", archive.url, sha256));
+                }
+                if ArchiveFormat::detect(&archive.url).is_none() {
+                    return Err(format!(
+                        "Cannot detect the archive format of {}, expected .tar.gz, .tgz or .zip",
+                        archive.url
+                    ));
+                }
+                for pattern in &archive.include {
+                    globset::Glob::new(pattern)
+                        .map_err(|e| format!("Invalid include pattern {:?}: {}", pattern, e))?;
+                }
+
+                Renderer::new(host, &self.vars, false)
+                    .render_path(&archive.dest)
+                    .map_err(|e| {
+                        format!(
+                            "Archive destination {:?} cannot be resolved: {}",
+                            archive.dest,
+                            template::error_chain(&e)
+                        )
+                    })?;
+            }
+            FixtureType::Gitconfig(gitconfig) => {
+                if gitconfig.keys.is_empty() {
+                    return Err("Gitconfig fixture must set at least one key".to_string());
+                }
+                for key in gitconfig.keys.keys() {
+                    // `section.name`, or `section.subsection.name` where the subsection can
+                    // contain dots itself
+                    match key.rsplit_once('.') {
+                        Some((section, name)) if !section.is_empty() && !name.is_empty() => {}
+                        _ => return Err(format!("Invalid git config key {:?}", key)),
+                    }
+                }
+
+                Renderer::new(host, &self.vars, false)
+                    .render_path(&gitconfig.path)
+                    .map_err(|e| {
+                        format!(
+                            "Git config path {:?} cannot be resolved: {}",
+                            gitconfig.path,
+                            template::error_chain(&e)
+                        )
+                    })?;
             }
         }
 
@@ -54,14 +191,18 @@ impl Fixture {
     }
 
     pub fn skip(&self) -> bool {
+        self.skip_for(&Host::current())
+    }
+
+    pub fn skip_for(&self, host: &Host) -> bool {
         if let Some(exclude_for) = &self.exclude_for {
-            if matches_spec(exclude_for) {
+            if matches_spec(exclude_for, host) {
                 return true;
             }
         }
 
         if let Some(include_for) = &self.include_for {
-            if !matches_spec(include_for) {
+            if !matches_spec(include_for, host) {
                 return true;
             }
         }
@@ -76,6 +217,11 @@ impl Fixture {
 pub enum FixtureType {
     Files(FilesSetup),
     Repository(RepositorySetup),
+    Systemd(SystemdSetup),
+    Packages(PackagesSetup),
+    Command(CommandSetup),
+    Archive(ArchiveSetup),
+    Gitconfig(GitConfigSetup),
 }
 
 impl Default for FixtureType {
@@ -92,12 +238,22 @@ pub struct FilesSetup {
     #[serde(default)]
     pub root: bool,
 
+    /// Allow templates to run commands with `command_output`
+    #[serde(default)]
+    pub allow_commands: bool,
+
+    /// Fail if a template references any variable that isn't defined
+    #[serde(default)]
+    pub strict: bool,
+
     #[serde(skip)]
-    pub secrets: HashMap<String, String>,
+    pub secrets: Secrets,
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct File {
+    /// Left out for files that should be absent
+    #[serde(default, skip_serializing_if = "FileDefinition::is_empty")]
     pub src: FileDefinition,
     pub dest: FileDefinition,
 
@@ -106,6 +262,155 @@ pub struct File {
 
     #[serde(default)]
     pub optional: bool,
+
+    /// User that owns the destination, by name or uid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+
+    /// Group that owns the destination, by name or gid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+
+    /// Permissions of the destination as an octal string like `"0640"`, instead of the source's
+    #[serde(default, with = "octal_mode", skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Strategy::is_replace")]
+    pub strategy: Strategy,
+
+    /// Comment prefix for the markers of a managed block, `#` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+
+    /// Format of a merged file, detected from the destination's extension by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<Format>,
+
+    #[serde(default, skip_serializing_if = "State::is_present")]
+    pub state: State,
+
+    /// Remove everything in a directory that the fixture doesn't manage
+    #[serde(default)]
+    pub purge: bool,
+}
+
+/// Whether the destination should exist
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    #[default]
+    Present,
+    /// Remove the destination, backing it up first
+    Absent,
+    /// Create the destination as a directory
+    Directory,
+}
+
+/// How the rendered source is written to the destination
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// Replace the whole destination
+    #[default]
+    Replace,
+    /// Insert or update a marked block, leaving the rest of the destination untouched
+    Block,
+    /// Deep merge the source into the destination, keeping keys the source doesn't set
+    Merge,
+    /// Apply the source, a unified diff, to the destination
+    Patch,
+}
+
+/// Structured formats that can be merged
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+    Ini,
+}
+
+impl Format {
+    pub fn detect(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(Format::Json),
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "ini" | "cfg" => Some(Format::Ini),
+            _ => None,
+        }
+    }
+}
+
+impl Strategy {
+    pub fn is_replace(&self) -> bool {
+        *self == Strategy::Replace
+    }
+}
+
+impl State {
+    pub fn is_present(&self) -> bool {
+        *self == State::Present
+    }
+}
+
+impl File {
+    /// Whether writing the file needs root, regardless of the fixture's `root` setting
+    pub fn needs_privilege(&self) -> bool {
+        self.owner.is_some() || self.group.is_some()
+    }
+}
+
+#[cfg(test)]
+impl File {
+    /// An entry with every option at its default
+    pub fn new(src: FileDefinition, dest: FileDefinition) -> Self {
+        Self {
+            src,
+            dest,
+            raw: false,
+            optional: false,
+            owner: None,
+            group: None,
+            mode: None,
+            strategy: Strategy::Replace,
+            comment: None,
+            format: None,
+            state: State::Present,
+            purge: false,
+        }
+    }
+}
+
+mod octal_mode {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(mode: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+        match mode {
+            Some(mode) => serializer.serialize_str(&format!("{:04o}", mode)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u32>, D::Error> {
+        let Some(mode) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+
+        u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .map(Some)
+            .ok_or_else(|| {
+                D::Error::custom(format!(
+                    "invalid mode {:?}, expected octal like \"0644\"",
+                    mode
+                ))
+            })
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -115,15 +420,34 @@ pub enum FileDefinition {
     Multiple(HashMap<Specifier, PathBuf>),
 }
 
+/// A definition that resolves for no host, used when a file has no source
+impl Default for FileDefinition {
+    fn default() -> Self {
+        FileDefinition::Multiple(HashMap::new())
+    }
+}
+
 impl FileDefinition {
+    pub fn is_empty(&self) -> bool {
+        matches!(self, FileDefinition::Multiple(map) if map.is_empty())
+    }
+
     pub fn resolve(self) -> Option<PathBuf> {
+        self.resolve_for(&Host::current())
+    }
+
+    pub fn resolve_for(self, host: &Host) -> Option<PathBuf> {
+        self.select_for(host)
+            .map(|path| path.resolve().to_path_buf())
+    }
+
+    /// Choose the path for the host as written in the fixture, without resolving it
+    pub fn select_for(self, host: &Host) -> Option<PathBuf> {
         match self {
-            FileDefinition::Single(path) => Some(path.resolve().to_path_buf()),
+            FileDefinition::Single(path) => Some(path),
             FileDefinition::Multiple(map) => {
-                if let Some(spec) = choose_spec(&map.keys().cloned().collect::<Vec<_>>()) {
-                    map.get(&spec)
-                        .cloned()
-                        .map(|path| path.resolve().to_path_buf())
+                if let Some(spec) = choose_spec(&map.keys().cloned().collect::<Vec<_>>(), host) {
+                    map.get(&spec).cloned()
                 } else {
                     None
                 }
@@ -146,41 +470,46 @@ impl FileDefinition {
     }
 }
 
-fn choose_spec(specs: &[Specifier]) -> Option<Specifier> {
-    use std::env::consts::{ARCH, OS};
-    if let Ok(hostname) = hostname::get() {
-        if let Some(hostname) = hostname.to_string_lossy().split('.').next() {
-            // Hostname exact match
-            if specs.contains(&hostname.to_string()) {
-                return Some(hostname.to_string());
-            }
+fn choose_spec(specs: &[Specifier], host: &Host) -> Option<Specifier> {
+    if let Some(hostname) = host.short_hostname() {
+        // Hostname exact match
+        if specs.contains(&hostname.to_string()) {
+            return Some(hostname.to_string());
+        }
 
-            // Hostname glob match
-            for spec in specs {
-                let glob = globset::Glob::new(spec)
-                    .expect("Invalid glob")
-                    .compile_matcher();
+        // Hostname glob match
+        for spec in specs {
+            let glob = globset::Glob::new(spec)
+                .expect("Invalid glob")
+                .compile_matcher();
 
-                if glob.is_match(hostname) {
-                    return Some(spec.clone());
-                }
+            if glob.is_match(hostname) {
+                return Some(spec.clone());
             }
         }
     }
 
+    // Tag match
+    for tag in &host.tags {
+        if specs.contains(tag) {
+            return Some(tag.clone());
+        }
+    }
+
     // OS-ARCH match
-    if specs.contains(&format!("{}-{}", OS, ARCH)) {
-        return Some(format!("{}-{}", OS, ARCH));
+    let os_arch = format!("{}-{}", host.os, host.arch);
+    if specs.contains(&os_arch) {
+        return Some(os_arch);
     }
 
     // OS match
-    if specs.contains(&OS.to_string()) {
-        return Some(OS.to_string());
+    if specs.contains(&host.os) {
+        return Some(host.os.clone());
     }
 
     // ARCH match
-    if specs.contains(&ARCH.to_string()) {
-        return Some(ARCH.to_string());
+    if specs.contains(&host.arch) {
+        return Some(host.arch.clone());
     }
 
     // Default case
@@ -191,8 +520,8 @@ fn choose_spec(specs: &[Specifier]) -> Option<Specifier> {
     None
 }
 
-fn matches_spec(specs: &[Specifier]) -> bool {
-    choose_spec(specs).is_some()
+fn matches_spec(specs: &[Specifier], host: &Host) -> bool {
+    choose_spec(specs, host).is_some()
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -211,6 +540,228 @@ pub enum Reference {
     Commit(String),
 }
 
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SystemdSetup {
+    #[serde(default)]
+    pub scope: Scope,
+
+    pub units: Vec<Unit>,
+}
+
+/// Which systemd instance manages the units
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// `systemctl --user`, with units in `~/.config/systemd/user`
+    #[default]
+    User,
+    /// The system instance, with units in `/etc/systemd/system` written as root
+    System,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Unit {
+    /// Unit file, installed under its own name
+    pub src: PathBuf,
+
+    /// Enable or disable the unit, left alone if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+
+    /// Start or stop the unit, left alone if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started: Option<bool>,
+}
+
+impl Unit {
+    pub fn name(&self) -> String {
+        self.src.file_name().unwrap().to_string_lossy().to_string()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PackagesSetup {
+    pub packages: PackageDefinition,
+}
+
+/// Package names for each package manager
+pub type PackageLists = BTreeMap<Manager, Vec<String>>;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum PackageDefinition {
+    Single(PackageLists),
+    Multiple(HashMap<Specifier, PackageLists>),
+}
+
+impl PackageDefinition {
+    /// Choose the package lists for the host
+    pub fn select_for(&self, host: &Host) -> Option<&PackageLists> {
+        match self {
+            PackageDefinition::Single(lists) => Some(lists),
+            PackageDefinition::Multiple(map) => {
+                let spec = choose_spec(&map.keys().cloned().collect::<Vec<_>>(), host)?;
+                map.get(&spec)
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let empty = |lists: &PackageLists| lists.values().all(Vec::is_empty);
+        match self {
+            PackageDefinition::Single(lists) => empty(lists),
+            PackageDefinition::Multiple(map) => map.values().all(empty),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CommandSetup {
+    /// Shell script, run with `sh -c`
+    pub command: String,
+
+    /// Skip the command if this path exists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creates: Option<PathBuf>,
+
+    /// Skip the command if this check succeeds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unless: Option<String>,
+
+    /// Only run the command if this check succeeds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub only_if: Option<String>,
+
+    /// Run the command only once on each machine, or again once it changes
+    #[serde(default)]
+    pub run_once: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ArchiveSetup {
+    /// `https://`, `http://` or `file://` URL, or a path relative to the fixture
+    pub url: String,
+
+    /// Checksum the downloaded archive must match
+    pub sha256: String,
+
+    /// Directory the archive is extracted into
+    pub dest: PathBuf,
+
+    /// Leading path components to remove from each entry, like `tar --strip-components`
+    #[serde(default)]
+    pub strip_components: usize,
+
+    /// Only extract entries matching one of these globs, after stripping components
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+}
+
+/// Archive formats that can be extracted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn detect(url: &str) -> Option<Self> {
+        if url.ends_with(".tar.gz") || url.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if url.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct GitConfigSetup {
+    /// Config file the keys are set in
+    #[serde(default = "GitConfigSetup::default_path")]
+    pub path: PathBuf,
+
+    /// Values by key, like `user.email` or `includeIf.gitdir:~/work/.path`. Keys that aren't
+    /// listed are left alone.
+    pub keys: BTreeMap<String, GitConfigValue>,
+}
+
+impl GitConfigSetup {
+    fn default_path() -> PathBuf {
+        PathBuf::from("~/.gitconfig")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum GitConfigValue {
+    Single(Scalar),
+    /// A multi-valued key, set to exactly these values in order
+    Multiple(Vec<Scalar>),
+    Specified(HashMap<Specifier, GitConfigValue>),
+}
+
+impl GitConfigValue {
+    /// Choose the values of the key for the host, or `None` if no specifier matches
+    pub fn select_for(&self, host: &Host) -> Option<Vec<String>> {
+        match self {
+            GitConfigValue::Single(value) => Some(vec![value.to_string()]),
+            GitConfigValue::Multiple(values) => {
+                Some(values.iter().map(|value| value.to_string()).collect())
+            }
+            GitConfigValue::Specified(map) => {
+                let spec = choose_spec(&map.keys().cloned().collect::<Vec<_>>(), host)?;
+                map.get(&spec)?.select_for(host)
+            }
+        }
+    }
+}
+
+/// A git config value, which may be written as a plain boolean or number
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Scalar {
+    Bool(bool),
+    Int(i64),
+    String(String),
+}
+
+impl std::fmt::Display for Scalar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scalar::Bool(value) => write!(f, "{}", value),
+            Scalar::Int(value) => write!(f, "{}", value),
+            Scalar::String(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Manager {
+    Pacman,
+    Apt,
+    Dnf,
+    Emerge,
+    Cargo,
+    Flatpak,
+}
+
+impl std::fmt::Display for Manager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Manager::Pacman => "pacman",
+            Manager::Apt => "apt",
+            Manager::Dnf => "dnf",
+            Manager::Emerge => "emerge",
+            Manager::Cargo => "cargo",
+            Manager::Flatpak => "flatpak",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -229,13 +780,13 @@ mod tests {
         }"#;
 
         let expected = FilesSetup {
-            files: vec![File {
-                src: FileDefinition::Single(PathBuf::from("src")),
-                dest: FileDefinition::Single(PathBuf::from("dest")),
-                raw: false,
-                optional: false,
-            }],
+            files: vec![File::new(
+                FileDefinition::Single(PathBuf::from("src")),
+                FileDefinition::Single(PathBuf::from("dest")),
+            )],
             root: true,
+            allow_commands: false,
+            strict: false,
             secrets: HashMap::new(),
         };
 
@@ -243,6 +794,54 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_deserialize_file_ownership() {
+        let input = r#"{
+            "src": "wheel",
+            "dest": "/etc/sudoers.d/wheel",
+            "owner": "root",
+            "group": "root",
+            "mode": "0440"
+        }"#;
+
+        let file: File = serde_json::from_str(input).unwrap();
+        assert_eq!(file.owner.as_deref(), Some("root"));
+        assert_eq!(file.group.as_deref(), Some("root"));
+        assert_eq!(file.mode, Some(0o440));
+        assert!(file.needs_privilege());
+
+        let serialized = serde_json::to_value(&file).unwrap();
+        assert_eq!(serialized["mode"], "0440");
+
+        let invalid = r#"{"src": "a", "dest": "b", "mode": "rw-r--r--"}"#;
+        assert!(serde_json::from_str::<File>(invalid).is_err());
+    }
+
+    #[test]
+    fn test_validate_absent_file() {
+        let fixture: Fixture = serde_json::from_str(
+            r#"{
+                "type": "files",
+                "files": [{"dest": "~/.bash_profile", "state": "absent"}]
+            }"#,
+        )
+        .unwrap();
+        let FixtureType::Files(setup) = &fixture.fixture_type else {
+            unreachable!();
+        };
+        assert_eq!(setup.files[0].state, State::Absent);
+        assert!(fixture.validate().is_ok());
+
+        let with_source: Fixture = serde_json::from_str(
+            r#"{
+                "type": "files",
+                "files": [{"src": "profile", "dest": "~/.bash_profile", "state": "absent"}]
+            }"#,
+        )
+        .unwrap();
+        assert!(with_source.validate().is_err());
+    }
+
     #[test]
     fn test_deserialize_files_setup_multiple() {
         let input = r#"{
@@ -260,18 +859,18 @@ mod tests {
         }"#;
 
         let expected = FilesSetup {
-            files: vec![File {
-                src: FileDefinition::Multiple({
+            files: vec![File::new(
+                FileDefinition::Multiple({
                     let mut map = HashMap::new();
                     map.insert("windows".to_string(), PathBuf::from("src/windows"));
                     map.insert("default".to_string(), PathBuf::from("src/default"));
                     map
                 }),
-                dest: FileDefinition::Single(PathBuf::from("dest")),
-                raw: false,
-                optional: false,
-            }],
+                FileDefinition::Single(PathBuf::from("dest")),
+            )],
             root: true,
+            allow_commands: false,
+            strict: false,
             secrets: HashMap::new(),
         };
 
@@ -301,6 +900,140 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_deserialize_systemd_setup() {
+        let input = r#"{
+            "type": "systemd",
+            "units": [
+                {
+                    "src": "syncthing.service",
+                    "enabled": true,
+                    "started": true
+                }
+            ]
+        }"#;
+
+        let expected = SystemdSetup {
+            scope: Scope::User,
+            units: vec![Unit {
+                src: PathBuf::from("syncthing.service"),
+                enabled: Some(true),
+                started: Some(true),
+            }],
+        };
+
+        let FixtureType::Systemd(actual) = serde_json::from_str(input).unwrap() else {
+            panic!("expected a systemd fixture");
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_select_packages() {
+        let input = r#"{
+            "type": "packages",
+            "packages": {
+                "desktop": {"pacman": ["neovim", "ripgrep"], "cargo": ["bat"]},
+                "default": {"apt": ["neovim"]}
+            }
+        }"#;
+
+        let FixtureType::Packages(setup) = serde_json::from_str(input).unwrap() else {
+            panic!("expected a packages fixture");
+        };
+        let desktop = Host::current().with_overrides(Some("desktop".into()), None, None);
+        let laptop = Host::current().with_overrides(Some("laptop".into()), None, None);
+
+        assert_eq!(
+            setup.packages.select_for(&desktop),
+            Some(&PackageLists::from([
+                (Manager::Pacman, vec!["neovim".into(), "ripgrep".into()]),
+                (Manager::Cargo, vec!["bat".into()]),
+            ]))
+        );
+        assert_eq!(
+            setup.packages.select_for(&laptop),
+            Some(&PackageLists::from([(Manager::Apt, vec!["neovim".into()])]))
+        );
+
+        let single: PackagesSetup =
+            serde_json::from_str(r#"{"packages": {"flatpak": ["org.gimp.GIMP"]}}"#).unwrap();
+        assert!(matches!(single.packages, PackageDefinition::Single(_)));
+    }
+
+    #[test]
+    fn test_select_gitconfig_values() {
+        let input = r#"{
+            "type": "gitconfig",
+            "keys": {
+                "core.autocrlf": false,
+                "credential.helper": ["", "store"],
+                "user.email": {"work": "me@work.example", "default": "me@home.example"},
+                "user.signingkey": {"work": "ABCD1234"}
+            }
+        }"#;
+
+        let FixtureType::Gitconfig(setup) = serde_json::from_str(input).unwrap() else {
+            panic!("expected a gitconfig fixture");
+        };
+        assert_eq!(setup.path, PathBuf::from("~/.gitconfig"));
+
+        let work = Host::current().with_overrides(Some("work".into()), None, None);
+        let home = Host::current().with_overrides(Some("home".into()), None, None);
+        let select = |key: &str, host: &Host| setup.keys[key].select_for(host);
+
+        assert_eq!(select("core.autocrlf", &home), Some(vec!["false".into()]));
+        assert_eq!(
+            select("credential.helper", &home),
+            Some(vec!["".into(), "store".into()])
+        );
+        assert_eq!(
+            select("user.email", &work),
+            Some(vec!["me@work.example".into()])
+        );
+        assert_eq!(
+            select("user.email", &home),
+            Some(vec!["me@home.example".into()])
+        );
+        assert_eq!(select("user.signingkey", &home), None);
+    }
+
+    #[test]
+    fn test_validate_duplicate_blocks() {
+        let fixture: Fixture = serde_json::from_str(
+            r#"{
+                "type": "files",
+                "files": [
+                    {"src": "hosts", "dest": "/etc/hosts", "strategy": "block", "optional": true},
+                    {"src": "nas", "dest": "/etc/hosts", "strategy": "block", "optional": true}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let error = fixture.validate().unwrap_err();
+        assert!(error.contains("/etc/hosts"), "{}", error);
+    }
+
+    #[test]
+    fn test_validate_unresolvable_dest() {
+        let fixture: Fixture = serde_json::from_str(
+            r#"{
+                "type": "files",
+                "files": [
+                    {
+                        "src": "src",
+                        "dest": "{{ xdg_config_home }}/{{ app_name }}/config"
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let error = fixture.validate().unwrap_err();
+        assert!(error.contains("`app_name`"), "{}", error);
+    }
+
     #[test]
     fn test_choose_spec_os() {
         let specs = vec![
@@ -309,7 +1042,10 @@ mod tests {
             "linux".to_string(),
         ];
 
-        assert_eq!(choose_spec(&specs), Some(std::env::consts::OS.to_string()));
+        assert_eq!(
+            choose_spec(&specs, &Host::current()),
+            Some(std::env::consts::OS.to_string())
+        );
     }
 
     #[test]
@@ -320,7 +1056,7 @@ mod tests {
             "solaris".to_string(),
         ];
 
-        assert_eq!(choose_spec(&specs), None);
+        assert_eq!(choose_spec(&specs, &Host::current()), None);
     }
 
     #[test]
@@ -331,7 +1067,10 @@ mod tests {
             "default".to_string(),
         ];
 
-        assert_eq!(choose_spec(&specs), Some("default".to_string()));
+        assert_eq!(
+            choose_spec(&specs, &Host::current()),
+            Some("default".to_string())
+        );
     }
 
     #[test]
@@ -344,7 +1083,10 @@ mod tests {
             format!("{}-arm", OS),
         ];
 
-        assert_eq!(choose_spec(&specs), Some(format!("{}-{}", OS, ARCH)));
+        assert_eq!(
+            choose_spec(&specs, &Host::current()),
+            Some(format!("{}-{}", OS, ARCH))
+        );
     }
 
     #[test]
@@ -356,7 +1098,7 @@ mod tests {
         ];
 
         assert_eq!(
-            choose_spec(&specs),
+            choose_spec(&specs, &Host::current()),
             Some(std::env::consts::ARCH.to_string())
         );
     }
@@ -371,7 +1113,7 @@ mod tests {
             hostname.clone(),
         ];
 
-        assert_eq!(choose_spec(&specs), Some(hostname));
+        assert_eq!(choose_spec(&specs, &Host::current()), Some(hostname));
     }
 
     #[test]
@@ -387,7 +1129,7 @@ mod tests {
             glob.clone(),
         ];
 
-        assert_eq!(choose_spec(&specs), Some(glob));
+        assert_eq!(choose_spec(&specs, &Host::current()), Some(glob));
     }
 
     #[test]
@@ -405,6 +1147,14 @@ mod tests {
             hostname.clone(),
         ];
 
-        assert_eq!(choose_spec(&specs), Some(hostname));
+        assert_eq!(choose_spec(&specs, &Host::current()), Some(hostname));
+    }
+
+    #[test]
+    fn test_choose_spec_tag() {
+        let host = Host::current().with_tags(vec!["work".into()]);
+        let specs = vec![std::env::consts::OS.to_string(), "work".to_string()];
+
+        assert_eq!(choose_spec(&specs, &host), Some("work".to_string()));
     }
 }
@@ -1,4 +1,7 @@
-use std::{path::PathBuf, process::Command};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
 
 use crate::fixture::Reference;
 
@@ -31,3 +34,109 @@ pub fn pull(path: &PathBuf) {
     cmd.current_dir(path);
     cmd.output().expect("failed to execute git pull");
 }
+
+/// Whether `path` is inside a git work tree
+pub fn is_repository(path: &Path) -> bool {
+    Command::new("git")
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .current_dir(path)
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Files tracked by git, relative to `path`
+pub fn tracked_files(path: &Path) -> Vec<PathBuf> {
+    let Ok(output) = Command::new("git")
+        .args(["ls-files", "-z"])
+        .current_dir(path)
+        .output()
+    else {
+        return vec![];
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .split('\0')
+        .filter(|file| !file.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Whether `file` is matched by an ignore rule, even if it is already tracked
+pub fn is_ignored(path: &Path, file: &Path) -> bool {
+    Command::new("git")
+        .args(["check-ignore", "-q", "--no-index"])
+        .arg(file)
+        .current_dir(path)
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn config(file: &Path) -> Command {
+    let mut cmd = Command::new("git");
+    cmd.arg("config").arg("--file").arg(file);
+    cmd
+}
+
+fn config_error(key: &str, output: &std::process::Output) -> std::io::Error {
+    std::io::Error::other(format!(
+        "git config failed for {}: {}",
+        key,
+        String::from_utf8_lossy(&output.stderr).trim()
+    ))
+}
+
+/// All values of a key in a config file, in order, or none if it isn't set
+pub fn config_get_all(file: &Path, key: &str) -> std::io::Result<Vec<String>> {
+    if !file.exists() {
+        return Ok(vec![]);
+    }
+
+    let output = config(file).args(["--null", "--get-all", key]).output()?;
+    match output.status.code() {
+        Some(0) => {
+            let values = String::from_utf8_lossy(&output.stdout);
+            // every value ends with a NUL, values themselves can be empty
+            let mut values: Vec<_> = values.split('\0').map(String::from).collect();
+            values.pop();
+            Ok(values)
+        }
+        // the key isn't set
+        Some(1) => Ok(vec![]),
+        _ => Err(config_error(key, &output)),
+    }
+}
+
+/// Remove every value of a key from a config file
+pub fn config_unset_all(file: &Path, key: &str) -> std::io::Result<()> {
+    if !file.exists() {
+        return Ok(());
+    }
+
+    let output = config(file).args(["--unset-all", key]).output()?;
+    match output.status.code() {
+        // 5 means the key wasn't set
+        Some(0) | Some(5) => Ok(()),
+        _ => Err(config_error(key, &output)),
+    }
+}
+
+/// Replace every value of a key in a config file, creating the file if needed. If that fails
+/// part-way, the previous values are put back.
+pub fn config_set_all(file: &Path, key: &str, values: &[String]) -> std::io::Result<()> {
+    let previous = config_get_all(file, key)?;
+    replace_all(file, key, values).inspect_err(|_| {
+        let _ = replace_all(file, key, &previous);
+    })
+}
+
+fn replace_all(file: &Path, key: &str, values: &[String]) -> std::io::Result<()> {
+    config_unset_all(file, key)?;
+    for value in values {
+        let output = config(file).args(["--add", key, value]).output()?;
+        if !output.status.success() {
+            return Err(config_error(key, &output));
+        }
+    }
+
+    Ok(())
+}
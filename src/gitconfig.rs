@@ -0,0 +1,301 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use log::error;
+
+use crate::{fixture::GitConfigSetup, git, host::Host, template::Renderer};
+
+/// A key whose values in the config file differ from the ones the fixture sets
+#[derive(Debug, PartialEq)]
+pub struct Drift {
+    pub key: String,
+    pub current: Vec<String>,
+    pub desired: Vec<String>,
+}
+
+impl std::fmt::Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.key,
+            describe(&self.current),
+            describe(&self.desired)
+        )
+    }
+}
+
+fn describe(values: &[String]) -> String {
+    match values {
+        [] => "unset".to_string(),
+        [value] => format!("{:?}", value),
+        values => format!("{:?}", values),
+    }
+}
+
+/// The keys selected for the host whose values in the config file differ, with templates in the
+/// values rendered. Keys without a value for the host aren't checked.
+pub fn drift(
+    setup: &GitConfigSetup,
+    path: &Path,
+    host: &Host,
+    renderer: &Renderer,
+) -> std::io::Result<Vec<Drift>> {
+    let mut drift = Vec::new();
+    for (key, value) in &setup.keys {
+        let Some(values) = value.select_for(host) else {
+            continue;
+        };
+        let desired = values
+            .iter()
+            .map(|value| render(key, value, renderer))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let current = git::config_get_all(path, key)
+            .inspect_err(|_| error!("failed to read {} from {}", key, path.to_string_lossy()))?;
+        if current != desired {
+            drift.push(Drift {
+                key: key.clone(),
+                current,
+                desired,
+            });
+        }
+    }
+
+    Ok(drift)
+}
+
+/// The values each key had before a gitconfig fixture first set it, by config file, so revert
+/// can put them back
+pub struct GitConfigState {
+    path: PathBuf,
+    previous: BTreeMap<PathBuf, BTreeMap<String, Vec<String>>>,
+}
+
+impl GitConfigState {
+    pub fn state_file() -> PathBuf {
+        dirs::state_dir()
+            .unwrap()
+            .join("spaceconf")
+            .join("gitconfig.json")
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let previous = if path.exists() {
+            let content = std::fs::read_to_string(path)
+                .inspect_err(|_| error!("failed to read {}", &path.to_string_lossy()))?;
+            serde_json::from_str(&content)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            previous,
+        })
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        std::fs::create_dir_all(self.path.parent().unwrap()).inspect_err(|_| {
+            error!(
+                "failed to create parent directory(s): {}",
+                &self.path.to_string_lossy()
+            )
+        })?;
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.previous)?)
+            .inspect_err(|_| error!("failed to write {}", &self.path.to_string_lossy()))
+    }
+}
+
+/// Only values with template syntax are rendered, so values like shell aliases can contain
+/// braces without escaping them
+fn render(key: &str, value: &str, renderer: &Renderer) -> std::io::Result<String> {
+    if !value.contains("{{") && !value.contains("{%") {
+        return Ok(value.to_string());
+    }
+
+    renderer
+        .render_file(Path::new(key), value)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Set each drifting key to its values, leaving every other key in the file alone. The values a
+/// key had before it was first set are recorded for revert.
+pub fn apply(
+    setup: &GitConfigSetup,
+    path: &Path,
+    host: &Host,
+    renderer: &Renderer,
+    state: &mut GitConfigState,
+) -> std::io::Result<()> {
+    let drift = drift(setup, path, host, renderer)?;
+    if drift.is_empty() {
+        println!("{} is up to date", path.to_string_lossy());
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).inspect_err(|_| {
+            error!(
+                "failed to create parent directory(s): {}",
+                &parent.to_string_lossy()
+            )
+        })?;
+    }
+    // recorded before anything changes, so a failed apply can still be reverted
+    let previous = state.previous.entry(path.to_path_buf()).or_default();
+    for key in &drift {
+        previous
+            .entry(key.key.clone())
+            .or_insert_with(|| key.current.clone());
+    }
+    state.save()?;
+
+    for key in &drift {
+        println!("Setting {}", key);
+        git::config_set_all(path, &key.key, &key.desired)
+            .inspect_err(|_| error!("failed to set {} in {}", key.key, path.to_string_lossy()))?;
+    }
+
+    Ok(())
+}
+
+/// Put back the values every key the fixture set had before, unsetting the keys that weren't
+/// set. Keys it never changed are left alone.
+pub fn revert(
+    setup: &GitConfigSetup,
+    path: &Path,
+    state: &mut GitConfigState,
+) -> std::io::Result<()> {
+    let Some(previous) = state.previous.get_mut(path) else {
+        return Ok(());
+    };
+
+    for key in setup.keys.keys() {
+        let Some(values) = previous.remove(key) else {
+            continue;
+        };
+        if git::config_get_all(path, key)? == values {
+            continue;
+        }
+
+        if values.is_empty() {
+            println!("Unsetting {} in {:?}", key, path);
+        } else {
+            println!("Restoring {} in {:?}", key, path);
+        }
+        git::config_set_all(path, key, &values)
+            .inspect_err(|_| error!("failed to restore {} in {}", key, path.to_string_lossy()))?;
+    }
+
+    if previous.is_empty() {
+        state.previous.remove(path);
+    }
+    state.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vars::Vars;
+
+    fn setup(path: &Path, keys: &str) -> GitConfigSetup {
+        GitConfigSetup {
+            path: path.to_path_buf(),
+            keys: serde_json::from_str(keys).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let path = test_dir.path().join(".gitconfig");
+        std::fs::write(
+            &path,
+            "[user]\n\tname = Someone\n[credential]\n\thelper = cache\n[include]\n\tpath = ~/.gitconfig.local\n",
+        )
+        .unwrap();
+        let setup = setup(
+            &path,
+            r#"{
+                "user.name": "Space Conf",
+                "core.autocrlf": false,
+                "credential.helper": ["", "store"],
+                "includeIf.gitdir:~/work/.path": "~/work/.gitconfig"
+            }"#,
+        );
+        let host = Host::current();
+        let renderer = Renderer::new(&host, &Vars::new(), false);
+        let mut state = GitConfigState::load(&test_dir.path().join("gitconfig.json")).unwrap();
+
+        let drift = drift(&setup, &path, &host, &renderer).unwrap();
+        assert_eq!(
+            drift.iter().map(|d| d.to_string()).collect::<Vec<_>>(),
+            vec![
+                r#"core.autocrlf: unset -> "false""#,
+                r#"credential.helper: "cache" -> ["", "store"]"#,
+                r#"includeIf.gitdir:~/work/.path: unset -> "~/work/.gitconfig""#,
+                r#"user.name: "Someone" -> "Space Conf""#,
+            ]
+        );
+
+        apply(&setup, &path, &host, &renderer, &mut state).unwrap();
+        assert!(super::drift(&setup, &path, &host, &renderer)
+            .unwrap()
+            .is_empty());
+        // keys the fixture doesn't declare are left alone
+        assert_eq!(
+            git::config_get_all(&path, "include.path").unwrap(),
+            vec!["~/.gitconfig.local"]
+        );
+
+        // the recorded values survive a reload
+        let mut state = GitConfigState::load(&test_dir.path().join("gitconfig.json")).unwrap();
+        revert(&setup, &path, &mut state).unwrap();
+        assert_eq!(
+            git::config_get_all(&path, "user.name").unwrap(),
+            vec!["Someone"]
+        );
+        assert_eq!(
+            git::config_get_all(&path, "credential.helper").unwrap(),
+            vec!["cache"]
+        );
+        assert!(git::config_get_all(&path, "core.autocrlf")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            git::config_get_all(&path, "include.path").unwrap(),
+            vec!["~/.gitconfig.local"]
+        );
+    }
+
+    #[test]
+    fn test_apply_per_host() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let path = test_dir.path().join("git/config");
+        let setup = setup(
+            &path,
+            r#"{
+                "user.email": {"work": "me@work.example", "default": "{{ hostname }}@home.example"},
+                "user.signingkey": {"work": "ABCD1234"}
+            }"#,
+        );
+        let host = Host::current().with_overrides(Some("laptop".into()), None, None);
+        let renderer = Renderer::new(&host, &Vars::new(), false);
+
+        let mut state = GitConfigState::load(&test_dir.path().join("gitconfig.json")).unwrap();
+
+        // the file and its directory are created
+        apply(&setup, &path, &host, &renderer, &mut state).unwrap();
+        assert_eq!(
+            git::config_get_all(&path, "user.email").unwrap(),
+            vec!["laptop@home.example"]
+        );
+        assert!(git::config_get_all(&path, "user.signingkey")
+            .unwrap()
+            .is_empty());
+    }
+}
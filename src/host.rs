@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+
+/// Facts about the machine that fixtures are selected and rendered for
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Host {
+    pub hostname: Option<String>,
+    pub os: String,
+    pub arch: String,
+
+    /// Arbitrary labels that can be used as specifiers, e.g. `work` or `laptop`
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Host {
+    pub fn current() -> Self {
+        Self {
+            hostname: hostname::get()
+                .ok()
+                .map(|hostname| hostname.to_string_lossy().to_string()),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            tags: vec![],
+        }
+    }
+
+    /// Replace any of the detected facts with the given values
+    pub fn with_overrides(
+        mut self,
+        hostname: Option<String>,
+        os: Option<String>,
+        arch: Option<String>,
+    ) -> Self {
+        if hostname.is_some() {
+            self.hostname = hostname;
+        }
+        if let Some(os) = os {
+            self.os = os;
+        }
+        if let Some(arch) = arch {
+            self.arch = arch;
+        }
+        self
+    }
+
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// The hostname without any domain part
+    pub fn short_hostname(&self) -> Option<&str> {
+        self.hostname
+            .as_deref()
+            .and_then(|hostname| hostname.split('.').next())
+    }
+}
+
+impl Default for Host {
+    fn default() -> Self {
+        Self::current()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_overrides() {
+        let host = Host::current().with_overrides(Some("desktop".into()), None, Some("arm".into()));
+
+        assert_eq!(host.hostname, Some("desktop".into()));
+        assert_eq!(host.os, std::env::consts::OS);
+        assert_eq!(host.arch, "arm");
+    }
+
+    #[test]
+    fn test_short_hostname() {
+        let host = Host::current().with_overrides(Some("desktop.example.com".into()), None, None);
+
+        assert_eq!(host.short_hostname(), Some("desktop"));
+    }
+}
@@ -1,23 +1,52 @@
-use fixture::{Fixture, FixtureType};
+use fixture::{Fixture, FixtureType, State};
+use host::Host;
+use ops::render;
 
+mod archive;
+mod command;
 mod fixture;
 pub mod git;
+mod gitconfig;
+pub mod host;
 pub mod ops;
+mod packages;
+pub mod privilege;
 mod repo;
+pub mod secrets;
+mod strategy;
+mod systemd;
 mod template;
+mod vars;
 
 pub fn list_fixtures(fixtures: Vec<Fixture>) {
     for fixture in fixtures {
-        match fixture.fixture_type {
+        match &fixture.fixture_type {
             FixtureType::Files(setup) => {
                 println!("Fixture: {}", fixture.name);
                 if setup.root {
                     println!("  Root: true");
                 }
-                for file in setup.files {
-                    if let Some(dest) = file.dest.resolve() {
-                        println!("  File: {}", dest.display());
+                let host = Host::current();
+                let renderer = match render::renderer(&fixture, setup, &host) {
+                    Ok(renderer) => renderer,
+                    Err(e) => {
+                        eprintln!("  Error: {}", secrets::redact(&e.to_string()));
+                        continue;
                     }
+                };
+                for file in &setup.files {
+                    if let Ok(Some(dest)) = render::resolve_dest(file, &host, &renderer) {
+                        match file.state {
+                            State::Present => println!("  File: {}", dest.display()),
+                            State::Absent => println!("  Absent: {}", dest.display()),
+                            State::Directory => println!("  Directory: {}", dest.display()),
+                        }
+                    }
+                }
+                let secrets = render::used_secrets(setup, &host, &renderer);
+                if !secrets.is_empty() {
+                    let names: Vec<_> = secrets.into_iter().collect();
+                    println!("  Secrets: {}", names.join(", "));
                 }
             }
             FixtureType::Repository(setup) => {
@@ -25,6 +54,40 @@ pub fn list_fixtures(fixtures: Vec<Fixture>) {
                 println!("  Reference: {:?}", setup.reference);
                 println!("  Path: {}", setup.path.display());
             }
+            FixtureType::Systemd(setup) => {
+                println!("Fixture: {}", fixture.name);
+                println!("  Scope: {:?}", setup.scope);
+                for unit in &setup.units {
+                    println!("  Unit: {}", unit.name());
+                }
+            }
+            FixtureType::Packages(setup) => {
+                println!("Fixture: {}", fixture.name);
+                if let Some(lists) = setup.packages.select_for(&Host::current()) {
+                    for (manager, packages) in lists {
+                        println!("  Packages ({}): {}", manager, packages.join(", "));
+                    }
+                }
+            }
+            FixtureType::Command(setup) => {
+                println!("Fixture: {}", fixture.name);
+                println!("  Command: {}", setup.command);
+                if setup.run_once {
+                    println!("  Run once: true");
+                }
+            }
+            FixtureType::Archive(setup) => {
+                println!("Fixture: {}", fixture.name);
+                println!("  Archive: {}", setup.url);
+                println!("  Path: {}", setup.dest.display());
+            }
+            FixtureType::Gitconfig(setup) => {
+                println!("Fixture: {}", fixture.name);
+                println!("  Git config: {}", setup.path.display());
+                for key in setup.keys.keys() {
+                    println!("  Key: {}", key);
+                }
+            }
         }
     }
 }
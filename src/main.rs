@@ -3,16 +3,26 @@ use std::path::PathBuf;
 use clap::{Parser, Subcommand};
 
 use spaceconf::git;
+use spaceconf::host::Host;
 use spaceconf::list_fixtures;
 use spaceconf::ops::apply::apply_fixtures;
 use spaceconf::ops::diff::diff_fixtures;
-use spaceconf::ops::load::load_fixtures;
+use spaceconf::ops::doctor::doctor;
+use spaceconf::ops::load::load_fixtures_for;
+use spaceconf::ops::render::{list_variables, render_fixtures};
+use spaceconf::ops::snapshot::test_snapshots;
+use spaceconf::privilege::Escalation;
+use spaceconf::secrets;
 
 #[derive(Parser)]
 #[command(version, about)]
 struct Args {
     #[command(subcommand)]
     command: Command,
+
+    /// Print secret values instead of masking them in diffs and other output
+    #[arg(long, global = true)]
+    show_secrets: bool,
 }
 
 #[derive(Subcommand)]
@@ -28,6 +38,28 @@ enum Command {
 
     /// Show differences between the current configuration and the fixtures
     Diff,
+
+    /// Render a fixture's files without applying them
+    Render(RenderArgs),
+
+    /// Compare rendered fixtures for the machines in machines.json to their snapshots
+    Test(TestArgs),
+
+    /// Check the repository for plaintext secrets that could be committed
+    Doctor(DoctorArgs),
+
+    /// Manage encrypted secrets
+    #[command(subcommand)]
+    Secrets(SecretsCommand),
+}
+
+#[derive(Subcommand)]
+enum SecretsCommand {
+    /// Decrypt a fixture's secrets, open them in $EDITOR and encrypt the result
+    Edit {
+        /// Fixture whose secrets to edit, or the secrets shared by all fixtures if omitted
+        fixture: Option<String>,
+    },
 }
 
 #[derive(Parser)]
@@ -48,12 +80,88 @@ struct ApplyArgs {
     /// Do not create a backup of the current configuration
     #[arg(short, long)]
     no_backup: bool,
+
+    /// Command used to write files of root fixtures, `none` writes directly
+    #[arg(long, value_enum, env = "SPACECONF_ESCALATE", default_value = "sudo")]
+    escalate: Escalation,
+
+    /// Print the systemctl commands for systemd fixtures instead of running them
+    #[arg(long)]
+    no_systemctl: bool,
+}
+
+#[derive(Parser)]
+struct RenderArgs {
+    /// Fixture to render
+    fixture: String,
+
+    /// Only render the file with this destination
+    #[arg(short, long)]
+    file: Option<PathBuf>,
+
+    /// Write rendered files into this directory instead of printing them
+    #[arg(short, long)]
+    out: Option<PathBuf>,
+
+    /// List the variables each template uses instead of rendering
+    #[arg(long)]
+    variables: bool,
+
+    #[command(flatten)]
+    host: HostArgs,
+}
+
+#[derive(Parser)]
+struct TestArgs {
+    /// Rewrite the snapshots instead of comparing against them
+    #[arg(short, long)]
+    update: bool,
+}
+
+#[derive(Parser)]
+struct DoctorArgs {
+    /// Add ignore rules for plaintext secrets files without asking
+    #[arg(long)]
+    fix: bool,
+
+    /// Also scan source files for strings that look like keys or tokens
+    #[arg(long)]
+    scan: bool,
+}
+
+#[derive(Parser)]
+struct HostArgs {
+    /// Render as if running on the machine with this hostname
+    #[arg(long)]
+    hostname: Option<String>,
+
+    /// Render as if running on this operating system
+    #[arg(long)]
+    os: Option<String>,
+
+    /// Render as if running on this architecture
+    #[arg(long)]
+    arch: Option<String>,
+
+    /// Render as if the machine had this tag
+    #[arg(long = "tag")]
+    tags: Vec<String>,
+}
+
+impl HostArgs {
+    fn host(&self) -> Host {
+        Host::current()
+            .with_overrides(self.hostname.clone(), self.os.clone(), self.arch.clone())
+            .with_tags(self.tags.clone())
+    }
 }
 
 fn main() {
-    env_logger::init();
+    // warnings like plaintext secrets that could be committed are shown by default
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
 
     let cli = Args::parse();
+    secrets::show_secrets(cli.show_secrets);
 
     let repo_dir = get_repo_dir();
 
@@ -73,22 +181,99 @@ fn main() {
         std::process::exit(1);
     }
 
-    let fixture_names = match cli.command {
-        Command::Apply(ref args) => args.fixtures.clone(),
-        _ => vec![],
+    if let Command::Test(args) = &cli.command {
+        match test_snapshots(&repo_dir, args.update) {
+            Ok(true) => std::process::exit(0),
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Command::Doctor(args) = &cli.command {
+        match doctor(&repo_dir, args.fix, args.scan) {
+            Ok(true) => std::process::exit(0),
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Command::Secrets(SecretsCommand::Edit { fixture }) = &cli.command {
+        let secrets_dir = match fixture {
+            Some(fixture) if !repo_dir.join(fixture).join("fixture.json").exists() => {
+                eprintln!("Fixture '{}' does not exist", fixture);
+                std::process::exit(1);
+            }
+            Some(fixture) => repo_dir.join(fixture),
+            None => repo_dir.clone(),
+        };
+
+        let editor = std::env::var("VISUAL")
+            .or_else(|_| std::env::var("EDITOR"))
+            .unwrap_or_else(|_| "vi".to_string());
+        match secrets::edit(&secrets_dir, &secrets::key_file(), &editor) {
+            Ok(_) => std::process::exit(0),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let (fixture_names, host) = match cli.command {
+        Command::Apply(ref args) => (args.fixtures.clone(), Host::current()),
+        Command::Render(ref args) => (vec![args.fixture.clone()], args.host.host()),
+        _ => (vec![], Host::current()),
     };
 
-    let fixtures = load_fixtures(get_repo_dir(), fixture_names).unwrap();
+    let fixtures = load_fixtures_for(get_repo_dir(), fixture_names, &host).unwrap();
 
     match cli.command {
         Command::List => {
             list_fixtures(fixtures);
         }
-        Command::Apply(args) => match apply_fixtures(fixtures, args.revert, args.no_backup) {
-            Ok(_) => println!("Configuration applied successfully"),
-            Err(e) => eprintln!("Error: {}", e),
-        },
-        Command::Diff => diff_fixtures(fixtures),
+        Command::Apply(args) => {
+            match apply_fixtures(
+                fixtures,
+                args.revert,
+                args.no_backup,
+                args.escalate,
+                args.no_systemctl,
+            ) {
+                Ok(_) => println!("Configuration applied successfully"),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Diff => {
+            if let Err(e) = diff_fixtures(fixtures) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Render(args) => {
+            if fixtures.is_empty() {
+                eprintln!("Fixture '{}' does not exist", args.fixture);
+                std::process::exit(1);
+            }
+
+            let result = if args.variables {
+                list_variables(fixtures, &host)
+            } else {
+                render_fixtures(fixtures, &host, args.file, args.out)
+            };
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
         _ => unimplemented!(),
     }
 }
@@ -96,3 +281,40 @@ fn main() {
 fn get_repo_dir() -> PathBuf {
     dirs::home_dir().unwrap().join(".spaceconf")
 }
+
+#[cfg(test)]
+mod tests {
+    use spaceconf::ops::render::render_fixture;
+
+    use super::*;
+
+    #[test]
+    fn test_render_with_tag() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let fixture_dir = test_dir.path().join("git");
+        std::fs::create_dir(&fixture_dir).unwrap();
+        std::fs::write(
+            fixture_dir.join("fixture.json"),
+            r#"{"type": "files", "files": [
+                {"src": "gitconfig", "dest": {"work": "/work/.gitconfig", "default": "/home/.gitconfig"}}
+            ]}"#,
+        )
+        .unwrap();
+        std::fs::write(fixture_dir.join("gitconfig"), "").unwrap();
+
+        let Command::Render(args) =
+            Args::parse_from(["spaceconf", "render", "git", "--tag", "work"]).command
+        else {
+            panic!("expected the render command");
+        };
+        let host = args.host.host();
+        assert_eq!(host.tags, vec!["work".to_string()]);
+
+        let fixtures = load_fixtures_for(test_dir.path().to_path_buf(), vec![], &host).unwrap();
+        let rendered = render_fixture(&fixtures[0], &host);
+        assert_eq!(
+            rendered[0].as_ref().unwrap().dest,
+            PathBuf::from("/work/.gitconfig")
+        );
+    }
+}
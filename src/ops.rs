@@ -1,3 +1,6 @@
 pub mod apply;
 pub mod diff;
+pub mod doctor;
 pub mod load;
+pub mod render;
+pub mod snapshot;
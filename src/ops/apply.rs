@@ -1,234 +1,809 @@
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
     io::Write,
-    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
     path::{Path, PathBuf},
 };
 
 use log::error;
+use serde::{Deserialize, Serialize};
 use termcolor::{ColorChoice, ColorSpec, StandardStream, WriteColor};
 
 use crate::{
-    fixture::{File, Fixture, FixtureType},
-    repo, template,
+    archive::{self, ArchiveState},
+    command::{self, RunOnce},
+    fixture::{File, Fixture, FixtureType, State, Strategy},
+    gitconfig::{self, GitConfigState},
+    host::Host,
+    ops::render,
+    packages::{self, System},
+    privilege::{self, Attributes, Escalation, PrivilegedBatch},
+    repo, strategy,
+    systemd::{self, Systemctl},
+    template::Renderer,
 };
 
 pub fn apply_fixtures(
     fixtures: Vec<Fixture>,
     revert: bool,
     no_backup: bool,
+    escalation: Escalation,
+    no_systemctl: bool,
 ) -> std::io::Result<()> {
     let backup_dir = dirs::state_dir().unwrap().join("spaceconf");
-    for fixture in fixtures {
+    let mut batch = PrivilegedBatch::new(escalation);
+    let mut failures = Vec::new();
+    // packages come first and are installed right away, so the fixtures using them find them
+    let is_packages = |fixture: &&Fixture| matches!(fixture.fixture_type, FixtureType::Packages(_));
+    let packages_first = fixtures
+        .iter()
+        .filter(is_packages)
+        .chain(fixtures.iter().filter(|fixture| !is_packages(fixture)));
+    for fixture in packages_first {
         if fixture.skip() {
             continue;
         }
 
-        match fixture.fixture_type {
+        match &fixture.fixture_type {
             FixtureType::Files(setup) => {
-                for file in setup.clone().files {
-                    apply_file(
-                        &file,
-                        &backup_dir,
-                        setup.root,
-                        &setup.secrets,
-                        revert,
-                        no_backup,
-                    )?;
+                let renderer = match render::renderer(fixture, setup, &Host::current()) {
+                    Ok(renderer) => renderer,
+                    Err(e) => {
+                        failures.push((fixture.name.clone(), e));
+                        continue;
+                    }
+                };
+                // worked out when the fixture is applied, to include archives extracted earlier
+                let managed = if setup.files.iter().any(|file| file.purge) {
+                    render::all_managed_paths(&fixtures, &Host::current())
+                } else {
+                    Vec::new()
+                };
+                let options = ApplyOptions {
+                    fixture: &fixture.name,
+                    backup_dir: &backup_dir,
+                    root: setup.root,
+                    revert,
+                    no_backup,
+                    managed: &managed,
+                };
+                for file in &setup.files {
+                    if let Err(e) = apply_file(file, &options, &renderer, &mut batch) {
+                        failures.push((fixture.name.clone(), e));
+                    }
                 }
             }
             FixtureType::Repository(setup) => {
-                repo::apply(setup.clone());
+                let mut setup = setup.clone();
+                match Renderer::new(&Host::current(), &fixture.vars, false).render_path(&setup.path)
+                {
+                    Ok(path) => setup.path = path,
+                    Err(e) => {
+                        failures.push((
+                            fixture.name.clone(),
+                            std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+                        ));
+                        continue;
+                    }
+                }
+                repo::apply(setup);
+            }
+            FixtureType::Systemd(setup) => {
+                let renderer = Renderer::new(&Host::current(), &fixture.vars, false);
+                let unit_dir = systemd::unit_dir(setup.scope);
+                let mut systemctl = Systemctl::new(setup.scope, no_systemctl);
+                let result = if revert {
+                    systemd::revert(setup, &unit_dir, &backup_dir, &mut systemctl, &mut batch)
+                } else {
+                    let backup_dir = (!no_backup).then_some(backup_dir.as_path());
+                    systemd::apply(
+                        setup,
+                        &unit_dir,
+                        backup_dir,
+                        &renderer,
+                        &mut systemctl,
+                        &mut batch,
+                    )
+                };
+                if let Err(e) = result {
+                    failures.push((fixture.name.clone(), e));
+                }
+            }
+            // installed packages are left alone when reverting
+            FixtureType::Packages(_) if revert => {}
+            FixtureType::Packages(setup) => {
+                let Some(lists) = setup.packages.select_for(&Host::current()) else {
+                    continue;
+                };
+                for (manager, packages) in lists {
+                    if let Err(e) = packages::install(&System(*manager), packages, &mut batch) {
+                        failures.push((fixture.name.clone(), e));
+                    }
+                }
+            }
+            // commands can't be undone
+            FixtureType::Command(_) if revert => {}
+            FixtureType::Command(setup) => {
+                let renderer = Renderer::new(&Host::current(), &fixture.vars, false);
+                let result = command::render(setup, &renderer).and_then(|rendered| {
+                    let mut run_once = RunOnce::load(&RunOnce::state_file())?;
+                    command::run(&fixture.name, &rendered, &mut run_once)
+                });
+                if let Err(e) = result {
+                    failures.push((fixture.name.clone(), e));
+                }
+            }
+            FixtureType::Archive(setup) => {
+                let renderer = Renderer::new(&Host::current(), &fixture.vars, false);
+                let result = renderer
+                    .render_path(&setup.dest)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                    .and_then(|dest| {
+                        let mut state = ArchiveState::load(&ArchiveState::state_file())?;
+                        if revert {
+                            archive::revert(&dest, &mut state)
+                        } else {
+                            archive::apply(setup, &dest, &archive::cache_dir(), &mut state)
+                        }
+                    });
+                if let Err(e) = result {
+                    failures.push((fixture.name.clone(), e));
+                }
+            }
+            FixtureType::Gitconfig(setup) => {
+                let host = Host::current();
+                let renderer = Renderer::new(&host, &fixture.vars, false);
+                let result = renderer
+                    .render_path(&setup.path)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                    .and_then(|path| {
+                        let mut state = GitConfigState::load(&GitConfigState::state_file())?;
+                        if revert {
+                            gitconfig::revert(setup, &path, &mut state)
+                        } else {
+                            gitconfig::apply(setup, &path, &host, &renderer, &mut state)
+                        }
+                    });
+                if let Err(e) = result {
+                    failures.push((fixture.name.clone(), e));
+                }
             }
         }
     }
 
-    Ok(())
+    // root files are written together at the end, so escalation only prompts once
+    if let Err(e) = batch.run() {
+        failures.push(("root files".to_string(), e));
+    }
+
+    render::report_failures(&failures)
 }
 
-fn apply_file(
-    file: &File,
-    backup_dir: &Path,
+struct ApplyOptions<'a> {
+    fixture: &'a str,
+    backup_dir: &'a Path,
     root: bool,
-    secrets: &HashMap<String, String>,
     revert: bool,
     no_backup: bool,
+    /// Destinations of every fixture's files and directories, kept when purging
+    managed: &'a [PathBuf],
+}
+
+fn apply_file(
+    file: &File,
+    options: &ApplyOptions,
+    renderer: &Renderer,
+    batch: &mut PrivilegedBatch,
 ) -> std::io::Result<()> {
+    let ApplyOptions {
+        fixture,
+        backup_dir,
+        root,
+        revert,
+        no_backup,
+        ..
+    } = *options;
+    match file.state {
+        State::Absent => return remove_dest(file, options, renderer, batch),
+        State::Directory => return apply_directory(file, options, renderer, batch),
+        State::Present => {}
+    }
+
     let mut stdout = StandardStream::stdout(ColorChoice::Auto);
     let Some(src) = file.src.clone().resolve() else {
         return Ok(());
     };
-    let Some(dest) = file.dest.clone().resolve() else {
+    let Some(dest) = render::resolve_dest(file, &Host::current(), renderer)? else {
         return Ok(());
     };
+    let privileged = root || file.needs_privilege();
+    let current = read_existing(&dest)?;
 
     stdout.set_color(ColorSpec::new().set_fg(Some(termcolor::Color::White)))?;
     if revert {
-        restore_file(backup_dir, &dest, root).inspect_err(|e| {
-            eprintln!("Failed to restore {:?}: {}", dest, e);
-        })
+        // only patches need the source to be undone
+        let rendered = match file.strategy {
+            Strategy::Patch => render::render_source(file, &src, renderer)?,
+            _ => String::new(),
+        };
+        let reverted = match &current {
+            Some(current) => strategy::revert(file, fixture, &rendered, current)?,
+            None => None,
+        };
+        let Some(reverted) = reverted else {
+            return restore_file(backup_dir, &dest, privileged.then_some(batch)).inspect_err(|e| {
+                eprintln!("Failed to restore {:?}: {}", dest, e);
+            });
+        };
+
+        if current.as_ref() != Some(&reverted) {
+            let mode = dest.metadata()?.mode();
+            write_dest(&dest, &reverted, &Attributes::new(mode), privileged, batch)?;
+            if privileged {
+                println!("Queued revert of {:?}", dest);
+            } else {
+                println!("Reverting {:?}", dest);
+            }
+        }
+        return Ok(());
+    }
+
+    let rendered = render::render_source(file, &src, renderer)?;
+    let output = strategy::apply(file, fixture, &dest, &rendered, current.as_deref())?;
+
+    // files spaceconf only partly manages keep their own permissions
+    let existing_mode = match file.strategy {
+        Strategy::Replace => None,
+        _ => dest
+            .metadata()
+            .ok()
+            .map(|metadata| metadata.mode() & 0o7777),
+    };
+    let attributes = Attributes {
+        mode: file
+            .mode
+            .or(existing_mode)
+            .unwrap_or_else(|| src.metadata().unwrap().mode() & 0o7777),
+        owner: file.owner.clone(),
+        group: file.group.clone(),
+    };
+
+    if current.as_ref() == Some(&output) && check_attributes(&attributes, &dest) {
+        stdout.set_color(ColorSpec::new().set_fg(Some(termcolor::Color::Green)))?;
+        writeln!(stdout, "{} is up to date", dest.to_string_lossy()).unwrap();
+        stdout.set_color(ColorSpec::new().set_fg(Some(termcolor::Color::White)))?;
+        return Ok(());
+    }
+
+    if !no_backup {
+        create_backup_dir(backup_dir)?;
+        backup_file(backup_dir, &dest)?;
+    }
+
+    write_dest(&dest, &output, &attributes, privileged, batch)?;
+    // root files are only written once the batch runs at the end
+    if privileged {
+        println!("Queued {:?}", dest);
     } else {
-        let output = if file.raw {
-            std::fs::read_to_string(&src)
-                .inspect_err(|_| error!("failed to read source file: {}", &src.to_string_lossy()))?
+        println!("Applying {:?}", dest);
+    }
+    Ok(())
+}
+
+/// Remove a destination that should be absent, or restore it from its backup when reverting
+fn remove_dest(
+    file: &File,
+    options: &ApplyOptions,
+    renderer: &Renderer,
+    batch: &mut PrivilegedBatch,
+) -> std::io::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Auto);
+    let Some(dest) = render::resolve_dest(file, &Host::current(), renderer)? else {
+        return Ok(());
+    };
+    let privileged = options.root || file.needs_privilege();
+
+    if options.revert {
+        // nothing to restore if the file never existed
+        if get_backup_filename(options.backup_dir, &dest)
+            .symlink_metadata()
+            .is_err()
+        {
+            return Ok(());
+        }
+        println!("Restoring {:?}", dest);
+        return restore_file(options.backup_dir, &dest, privileged.then_some(batch));
+    }
+
+    // symlink_metadata so dangling symlinks are removed too
+    if dest.symlink_metadata().is_err() {
+        stdout.set_color(ColorSpec::new().set_fg(Some(termcolor::Color::Green)))?;
+        writeln!(stdout, "{} is absent", dest.to_string_lossy()).unwrap();
+        stdout.set_color(ColorSpec::new().set_fg(Some(termcolor::Color::White)))?;
+        return Ok(());
+    }
+
+    if !options.no_backup {
+        create_backup_dir(options.backup_dir)?;
+        backup_file(options.backup_dir, &dest)?;
+    }
+
+    if privileged {
+        batch.remove(&dest);
+    } else {
+        std::fs::remove_file(&dest).inspect_err(|_| {
+            error!(
+                "failed to remove destination file: {}",
+                &dest.to_string_lossy()
+            )
+        })?;
+    }
+    println!("Removing {:?}", dest);
+    Ok(())
+}
+
+/// What a directory entry found when it first changed its directory, so reverting can put back
+/// exactly that
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+struct DirectoryRecord {
+    /// Mode and ownership the directory had, or `None` if it was created
+    previous: Option<PreviousAttributes>,
+
+    /// Paths purged from the directory that were backed up
+    #[serde(default)]
+    purged: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+struct PreviousAttributes {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+/// Directory entries that changed their directory, by destination, kept with the backups
+struct DirectoryState {
+    path: PathBuf,
+    directories: BTreeMap<PathBuf, DirectoryRecord>,
+}
+
+impl DirectoryState {
+    fn load(backup_dir: &Path) -> std::io::Result<Self> {
+        let path = backup_dir.join("directories.json");
+        let directories = if path.exists() {
+            let content = std::fs::read_to_string(&path)
+                .inspect_err(|_| error!("failed to read {}", &path.to_string_lossy()))?;
+            serde_json::from_str(&content)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
         } else {
-            let input = std::fs::read_to_string(&src).inspect_err(|_| {
-                error!("failed to read source file: {}", &src.to_string_lossy())
-            })?;
-            template::render(&input, secrets).unwrap()
+            BTreeMap::new()
         };
 
-        if check_content(&output, &dest) && check_mode(&src, &dest) {
-            stdout.set_color(ColorSpec::new().set_fg(Some(termcolor::Color::Green)))?;
-            writeln!(stdout, "{} is up to date", dest.to_string_lossy()).unwrap();
-            stdout.set_color(ColorSpec::new().set_fg(Some(termcolor::Color::White)))?;
-            return Ok(());
+        Ok(Self { path, directories })
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        create_backup_dir(self.path.parent().unwrap())?;
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.directories)?)
+            .inspect_err(|_| error!("failed to write {}", &self.path.to_string_lossy()))
+    }
+}
+
+/// Create a directory with its mode and ownership and purge what no fixture manages, or undo
+/// that when reverting
+fn apply_directory(
+    file: &File,
+    options: &ApplyOptions,
+    renderer: &Renderer,
+    batch: &mut PrivilegedBatch,
+) -> std::io::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Auto);
+    let Some(dest) = render::resolve_dest(file, &Host::current(), renderer)? else {
+        return Ok(());
+    };
+    let privileged = options.root || file.needs_privilege();
+
+    if options.revert {
+        return revert_directory(&dest, options.backup_dir, privileged.then_some(batch));
+    }
+
+    let previous = match dest.is_dir() {
+        true => dest.metadata().map(|metadata| {
+            Some(PreviousAttributes {
+                mode: metadata.mode() & 0o7777,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            })
+        })?,
+        false => None,
+    };
+    // without a mode, an existing directory keeps its own
+    let attributes = Attributes {
+        mode: file
+            .mode
+            .or(previous.map(|previous| previous.mode))
+            .unwrap_or(0o755),
+        owner: file.owner.clone(),
+        group: file.group.clone(),
+    };
+    let unmanaged = if file.purge && previous.is_some() {
+        render::unmanaged(&dest, options.managed)?
+    } else {
+        Vec::new()
+    };
+
+    let up_to_date = previous.is_some() && check_attributes(&attributes, &dest);
+    if up_to_date && unmanaged.is_empty() {
+        stdout.set_color(ColorSpec::new().set_fg(Some(termcolor::Color::Green)))?;
+        writeln!(stdout, "{} is up to date", dest.to_string_lossy()).unwrap();
+        stdout.set_color(ColorSpec::new().set_fg(Some(termcolor::Color::White)))?;
+        return Ok(());
+    }
+
+    // only the first change is recorded, later applies find the directory as it left it
+    let mut state = DirectoryState::load(options.backup_dir)?;
+    let record = state
+        .directories
+        .entry(dest.clone())
+        .or_insert_with(|| DirectoryRecord {
+            previous,
+            purged: Vec::new(),
+        });
+
+    if !up_to_date {
+        if privileged {
+            batch.create_dir(&dest, &attributes);
+        } else {
+            privilege::create_dir(&dest, &attributes)?;
+        }
+        if privileged {
+            println!("Queued {:?}", dest);
+        } else {
+            println!("Applying {:?}", dest);
         }
+    }
 
-        if !no_backup {
-            if !backup_dir.exists() {
-                std::fs::create_dir_all(backup_dir).inspect_err(|_| {
-                    error!(
-                        "failed to create parent directory(s): {}",
-                        &backup_dir.to_string_lossy()
-                    )
-                })?;
+    for path in &unmanaged {
+        if !options.no_backup {
+            create_backup_dir(options.backup_dir)?;
+            if privileged {
+                backup_tree_privileged(options.backup_dir, path, batch)?;
+            } else {
+                backup_tree(options.backup_dir, path)?;
+            }
+            if !record.purged.contains(path) {
+                record.purged.push(path.clone());
             }
-            backup_file(backup_dir, &dest);
         }
 
-        let mode = src.metadata().unwrap().mode();
-        if root {
-            write_root(&dest, &output, mode)?;
+        let is_dir = path.is_dir() && !path.is_symlink();
+        match (privileged, is_dir) {
+            (true, true) => batch.remove_dir(path),
+            (true, false) => batch.remove(path),
+            (false, true) => std::fs::remove_dir_all(path)?,
+            (false, false) => std::fs::remove_file(path)?,
+        }
+        println!("Purging {:?}", path);
+    }
+
+    state.save()
+}
+
+/// Restore what was purged from the directory and its previous mode and ownership, or remove
+/// it if it was created and is left empty
+fn revert_directory(
+    dest: &Path,
+    backup_dir: &Path,
+    mut batch: Option<&mut PrivilegedBatch>,
+) -> std::io::Result<()> {
+    let mut state = DirectoryState::load(backup_dir)?;
+    let Some(record) = state.directories.remove(dest) else {
+        return Ok(());
+    };
+
+    for purged in &record.purged {
+        if purged.symlink_metadata().is_ok() {
+            continue;
+        }
+
+        let backup = get_backup_filename(backup_dir, purged);
+        let mut backed_up = Vec::new();
+        if backup.symlink_metadata()?.is_dir() {
+            find_files(&backup, &mut backed_up)?;
         } else {
-            let mut open_options = std::fs::OpenOptions::new();
-            open_options.mode(mode);
-
-            std::fs::create_dir_all(dest.parent().unwrap()).inspect_err(|_| {
-                error!(
-                    "failed to create parent directory(s): {}",
-                    &dest.to_string_lossy()
-                )
-            })?;
-            let mut file = open_options
-                .write(true)
-                .truncate(true)
-                .create(true)
-                .open(&dest)
-                .inspect_err(|_| {
-                    error!(
-                        "failed to open destination file: {}",
-                        &dest.to_string_lossy()
-                    )
-                })?;
-            file.write_all(output.as_bytes()).inspect_err(|_| {
-                error!(
-                    "failed to write to destination file: {}",
-                    &dest.to_string_lossy()
-                )
-            })?;
+            backed_up.push(backup);
+        }
+        for file in backed_up {
+            let original = Path::new("/").join(file.strip_prefix(backup_dir).unwrap());
+            println!("Restoring {:?}", original);
+            restore_file(backup_dir, &original, batch.as_deref_mut())?;
         }
-        println!("Applying {:?}", dest);
-        Ok(())
     }
+
+    match record.previous {
+        Some(previous) => {
+            let current = dest.metadata().ok();
+            let attributes = Attributes {
+                mode: previous.mode,
+                owner: current
+                    .as_ref()
+                    .is_none_or(|current| current.uid() != previous.uid)
+                    .then(|| previous.uid.to_string()),
+                group: current
+                    .as_ref()
+                    .is_none_or(|current| current.gid() != previous.gid)
+                    .then(|| previous.gid.to_string()),
+            };
+            if !current.is_some_and(|_| check_attributes(&attributes, dest)) {
+                match batch {
+                    Some(batch) => batch.create_dir(dest, &attributes),
+                    None => privilege::create_dir(dest, &attributes)?,
+                }
+                println!("Restoring {:?}", dest);
+            }
+        }
+        // only removed when empty, so nothing that wasn't purged is lost
+        None if dest.is_dir() && std::fs::read_dir(dest)?.next().is_none() => {
+            match batch {
+                Some(batch) => batch.remove_dir(dest),
+                None => std::fs::remove_dir(dest)?,
+            }
+            println!("Removing {:?}", dest);
+        }
+        None => {}
+    }
+
+    state.save()
 }
 
-fn check_mode(src: &Path, dest: &Path) -> bool {
-    if !dest.exists() {
-        return false;
+fn find_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        // symlinks are backed up as they are, not followed
+        if entry.file_type()?.is_dir() {
+            find_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
     }
 
-    let Ok(src_metadata) = src.metadata() else {
-        return false;
-    };
-    let Ok(dest_metadata) = dest.metadata() else {
-        return false;
-    };
+    Ok(())
+}
 
-    src_metadata.mode() & 0x1777 == dest_metadata.mode() & 0x1777
+/// Back up a file, or every file in a directory
+fn backup_tree(backup_dir: &Path, path: &Path) -> std::io::Result<()> {
+    if path.is_dir() && !path.is_symlink() {
+        for entry in std::fs::read_dir(path)? {
+            backup_tree(backup_dir, &entry?.path())?;
+        }
+        return Ok(());
+    }
+
+    backup_file(backup_dir, path)
 }
 
-fn check_content(content: &str, output: &PathBuf) -> bool {
-    if !output.exists() {
-        return false;
+/// Copy a path the user may not be able to read into the backup directory as root, as part of
+/// the batch so it happens before the path is removed, and hand the copy to the user so it can
+/// be read when restoring
+fn backup_tree_privileged(
+    backup_dir: &Path,
+    path: &Path,
+    batch: &mut PrivilegedBatch,
+) -> std::io::Result<()> {
+    let backup = get_backup_filename(backup_dir, path);
+    std::fs::create_dir_all(backup.parent().unwrap()).inspect_err(|_| {
+        error!(
+            "failed to create parent directory(s): {}",
+            &backup.to_string_lossy()
+        )
+    })?;
+
+    let arg = |path: &Path| path.to_string_lossy().to_string();
+    // SAFETY: getuid and getgid always succeed
+    let owner = unsafe { format!("{}:{}", libc::getuid(), libc::getgid()) };
+    // copying onto an earlier backup of a directory would nest the copy inside it
+    batch.command(&["rm".into(), "-rf".into(), "--".into(), arg(&backup)]);
+    batch.command(&[
+        "cp".into(),
+        "-a".into(),
+        "--".into(),
+        arg(path),
+        arg(&backup),
+    ]);
+    batch.command(&[
+        "chown".into(),
+        "-hR".into(),
+        owner,
+        "--".into(),
+        arg(&backup),
+    ]);
+    Ok(())
+}
+
+fn create_backup_dir(backup_dir: &Path) -> std::io::Result<()> {
+    if backup_dir.exists() {
+        return Ok(());
     }
 
-    let existing_content = std::fs::read_to_string(output).unwrap();
+    std::fs::create_dir_all(backup_dir).inspect_err(|_| {
+        error!(
+            "failed to create parent directory(s): {}",
+            &backup_dir.to_string_lossy()
+        )
+    })
+}
+
+fn read_existing(dest: &Path) -> std::io::Result<Option<String>> {
+    if !dest.exists() {
+        return Ok(None);
+    }
 
-    content == existing_content
+    std::fs::read_to_string(dest).map(Some).inspect_err(|_| {
+        error!(
+            "failed to read destination file: {}",
+            &dest.to_string_lossy()
+        )
+    })
 }
 
-fn backup_file(backup_dir: &Path, file: &PathBuf) {
-    if !file.exists() {
-        return;
+/// Write the destination now, or queue it for the privileged batch
+fn write_dest(
+    dest: &Path,
+    content: &str,
+    attributes: &Attributes,
+    privileged: bool,
+    batch: &mut PrivilegedBatch,
+) -> std::io::Result<()> {
+    if privileged {
+        batch.write(dest, content, attributes);
+        return Ok(());
     }
 
+    let mut open_options = std::fs::OpenOptions::new();
+    open_options.mode(attributes.mode);
+
+    std::fs::create_dir_all(dest.parent().unwrap()).inspect_err(|_| {
+        error!(
+            "failed to create parent directory(s): {}",
+            &dest.to_string_lossy()
+        )
+    })?;
+    let mut file = open_options
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(dest)
+        .inspect_err(|_| {
+            error!(
+                "failed to open destination file: {}",
+                &dest.to_string_lossy()
+            )
+        })?;
+    file.write_all(content.as_bytes()).inspect_err(|_| {
+        error!(
+            "failed to write to destination file: {}",
+            &dest.to_string_lossy()
+        )
+    })?;
+    // the mode passed to open only applies to new files
+    file.set_permissions(std::fs::Permissions::from_mode(attributes.mode))
+}
+
+fn check_attributes(attributes: &Attributes, dest: &Path) -> bool {
+    let Ok(dest_metadata) = dest.metadata() else {
+        return false;
+    };
+
+    let owner_matches = match &attributes.owner {
+        Some(owner) => privilege::user_id(owner).is_ok_and(|uid| uid == dest_metadata.uid()),
+        None => true,
+    };
+    let group_matches = match &attributes.group {
+        Some(group) => privilege::group_id(group).is_ok_and(|gid| gid == dest_metadata.gid()),
+        None => true,
+    };
+
+    attributes.mode == dest_metadata.mode() & 0o7777 && owner_matches && group_matches
+}
+
+/// Copy a file to the same path under the backup directory. Symlinks are backed up as
+/// symlinks rather than as what they point to. Does nothing if the file doesn't exist.
+pub(crate) fn backup_file(backup_dir: &Path, file: &Path) -> std::io::Result<()> {
+    let Ok(metadata) = file.symlink_metadata() else {
+        return Ok(());
+    };
+
     let backup_file = get_backup_filename(backup_dir, file);
-    std::fs::create_dir_all(backup_file.parent().unwrap()).unwrap();
-    std::fs::copy(file, backup_file).unwrap();
+    std::fs::create_dir_all(backup_file.parent().unwrap()).inspect_err(|_| {
+        error!(
+            "failed to create parent directory(s): {}",
+            &backup_file.to_string_lossy()
+        )
+    })?;
+    // a symlink left by an earlier backup would otherwise be written through
+    if backup_file.is_symlink() {
+        std::fs::remove_file(&backup_file)?;
+    }
+
+    let result = if metadata.is_symlink() {
+        std::fs::read_link(file).and_then(|target| std::os::unix::fs::symlink(target, &backup_file))
+    } else {
+        std::fs::copy(file, &backup_file).map(|_| ())
+    };
+    result.inspect_err(|_| error!("failed to back up: {}", &file.to_string_lossy()))
 }
 
-fn restore_file(backup_dir: &Path, file: &PathBuf, root: bool) -> std::io::Result<()> {
+pub(crate) fn restore_file(
+    backup_dir: &Path,
+    file: &PathBuf,
+    root: Option<&mut PrivilegedBatch>,
+) -> std::io::Result<()> {
     let backup_file = get_backup_filename(backup_dir, file);
-    if !backup_file.exists() {
+    let Ok(metadata) = backup_file.symlink_metadata() else {
         eprintln!("Backup file does not exist for {:?}", file);
         return Err(std::io::Error::new(
             std::io::ErrorKind::NotFound,
             "Backup file does not exist",
         ));
-    }
+    };
 
-    let mode = backup_file.metadata().unwrap().mode();
+    if metadata.is_symlink() {
+        let target = std::fs::read_link(&backup_file)?;
+        return restore_symlink(&target, file, root);
+    }
 
-    if root {
-        write_root(file, &std::fs::read_to_string(backup_file).unwrap(), mode)?;
+    if let Some(batch) = root {
+        let content = std::fs::read_to_string(&backup_file)
+            .inspect_err(|_| error!("failed to read backup: {}", &backup_file.to_string_lossy()))?;
+        batch.write(file, &content, &Attributes::new(metadata.mode()));
     } else {
-        std::fs::copy(backup_file, file).unwrap();
+        // parents may have been removed along with the file, like purged directories
+        std::fs::create_dir_all(file.parent().unwrap())?;
+        // copying onto a symlink would write to its target instead
+        if file.is_symlink() {
+            std::fs::remove_file(file)?;
+        }
+        std::fs::copy(&backup_file, file)
+            .inspect_err(|_| error!("failed to restore: {}", &file.to_string_lossy()))?;
     }
     Ok(())
 }
 
-fn get_backup_filename(backup_dir: &Path, file: &Path) -> PathBuf {
-    backup_dir.join(file.strip_prefix("/").unwrap())
-}
-
-fn write_root(file: &PathBuf, content: &str, mode: u32) -> std::io::Result<()> {
-    #[cfg(not(target_os = "linux"))]
-    {
-        eprintln!("Root fixture is currently only supported on Linux");
-        std::process::exit(1);
+/// Put back a symlink that was backed up, replacing whatever is at its path
+fn restore_symlink(
+    target: &Path,
+    file: &Path,
+    root: Option<&mut PrivilegedBatch>,
+) -> std::io::Result<()> {
+    if let Some(batch) = root {
+        let arg = |path: &Path| path.to_string_lossy().to_string();
+        batch.command(&[
+            "mkdir".into(),
+            "-p".into(),
+            "--".into(),
+            arg(file.parent().unwrap()),
+        ]);
+        batch.command(&[
+            "ln".into(),
+            "-sfn".into(),
+            "--".into(),
+            arg(target),
+            arg(file),
+        ]);
+        return Ok(());
     }
 
-    let temp_file = PathBuf::from(format!("/tmp/spaceconf-{}.tmp", uuid::Uuid::new_v4()));
-    std::fs::write(&temp_file, content).inspect_err(|_| {
-        error!(
-            "failed to write temporary file: {}",
-            &temp_file.to_string_lossy()
-        )
-    })?;
-    if !file.parent().unwrap().exists() {
-        std::process::Command::new("sudo")
-            .arg("mkdir")
-            .arg("-p")
-            .arg(file.parent().unwrap())
-            .status()
-            .unwrap();
-    }
-    std::process::Command::new("sudo")
-        .arg("cp")
-        .arg(&temp_file)
-        .arg(file)
-        .status()
-        .unwrap();
-
-    std::process::Command::new("sudo")
-        .arg("chmod")
-        .arg(format!("{:o}", mode & 0o1777))
-        .arg(file)
-        .status()
-        .unwrap();
+    std::fs::create_dir_all(file.parent().unwrap())?;
+    if file.symlink_metadata().is_ok() {
+        std::fs::remove_file(file)?;
+    }
+    std::os::unix::fs::symlink(target, file)
+        .inspect_err(|_| error!("failed to restore: {}", &file.to_string_lossy()))
+}
 
-    Ok(())
+pub(crate) fn get_backup_filename(backup_dir: &Path, file: &Path) -> PathBuf {
+    backup_dir.join(file.strip_prefix("/").unwrap())
 }
 
 #[cfg(test)]
@@ -236,7 +811,29 @@ mod tests {
     use std::collections::HashMap;
 
     use super::*;
-    use crate::fixture::{self, FileDefinition, Fixture};
+    use crate::{
+        fixture::{self, FileDefinition, Fixture},
+        vars::Vars,
+    };
+
+    fn renderer() -> Renderer {
+        Renderer::new(&Host::current(), &Vars::new(), false)
+    }
+
+    fn options(backup_dir: &Path) -> ApplyOptions<'_> {
+        ApplyOptions {
+            fixture: "test",
+            backup_dir,
+            root: false,
+            revert: false,
+            no_backup: true,
+            managed: &[],
+        }
+    }
+
+    fn batch() -> PrivilegedBatch {
+        PrivilegedBatch::new(Escalation::None)
+    }
 
     #[test]
     fn test_apply_files_fixture() {
@@ -255,19 +852,24 @@ mod tests {
             name: "test-fixture".into(),
             include_for: None,
             exclude_for: None,
+            vars: Default::default(),
+            dir: Default::default(),
             fixture_type: FixtureType::Files(fixture::FilesSetup {
                 files: vec![fixture::File {
-                    src: FileDefinition::Single(source_file.clone()),
-                    dest: FileDefinition::Single(dest_file.clone()),
                     raw: true,
-                    optional: false,
+                    ..fixture::File::new(
+                        FileDefinition::Single(source_file.clone()),
+                        FileDefinition::Single(dest_file.clone()),
+                    )
                 }],
                 root: false,
+                allow_commands: false,
+                strict: false,
                 secrets: Default::default(),
             }),
         };
 
-        apply_fixtures(vec![fixture], false, true).unwrap();
+        apply_fixtures(vec![fixture], false, true, Escalation::None, false).unwrap();
 
         assert!(dest_file.exists());
 
@@ -276,6 +878,48 @@ mod tests {
         assert_eq!(dest_content, file_content);
     }
 
+    #[test]
+    fn test_apply_continues_after_template_error() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+
+        let broken_source = test_dir.path().join("broken.conf");
+        let source_file = test_dir.path().join("source.conf");
+        let broken_dest = test_dir.path().join("broken-dest.conf");
+        let dest_file = test_dir.path().join("dest.conf");
+
+        std::fs::write(&broken_source, "key = {{ API_KEY }}").unwrap();
+        std::fs::write(&source_file, "Hello, World!").unwrap();
+
+        let fixture = Fixture {
+            name: "test-fixture".into(),
+            include_for: None,
+            exclude_for: None,
+            vars: Default::default(),
+            dir: Default::default(),
+            fixture_type: FixtureType::Files(fixture::FilesSetup {
+                files: vec![
+                    fixture::File::new(
+                        FileDefinition::Single(broken_source),
+                        FileDefinition::Single(broken_dest.clone()),
+                    ),
+                    fixture::File::new(
+                        FileDefinition::Single(source_file),
+                        FileDefinition::Single(dest_file.clone()),
+                    ),
+                ],
+                root: false,
+                allow_commands: false,
+                strict: false,
+                secrets: Default::default(),
+            }),
+        };
+
+        assert!(apply_fixtures(vec![fixture], false, true, Escalation::None, false).is_err());
+
+        assert!(!broken_dest.exists());
+        assert!(dest_file.exists());
+    }
+
     #[test]
     fn test_apply_excluded_fixture() {
         let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
@@ -293,19 +937,24 @@ mod tests {
             name: "test-fixture".into(),
             include_for: None,
             exclude_for: Some(vec![std::env::consts::OS.into()]),
+            vars: Default::default(),
+            dir: Default::default(),
             fixture_type: FixtureType::Files(fixture::FilesSetup {
                 files: vec![fixture::File {
-                    src: FileDefinition::Single(source_file.clone()),
-                    dest: FileDefinition::Single(dest_file.clone()),
                     raw: true,
-                    optional: false,
+                    ..fixture::File::new(
+                        FileDefinition::Single(source_file.clone()),
+                        FileDefinition::Single(dest_file.clone()),
+                    )
                 }],
                 root: false,
+                allow_commands: false,
+                strict: false,
                 secrets: Default::default(),
             }),
         };
 
-        apply_fixtures(vec![fixture], false, true).unwrap();
+        apply_fixtures(vec![fixture], false, true, Escalation::None, false).unwrap();
 
         assert!(!dest_file.exists());
     }
@@ -327,22 +976,28 @@ mod tests {
             name: "test-fixture".into(),
             include_for: None,
             exclude_for: None,
+            vars: Default::default(),
+            dir: Default::default(),
             fixture_type: FixtureType::Files(fixture::FilesSetup {
                 files: vec![fixture::File {
-                    src: FileDefinition::Multiple(HashMap::from_iter(vec![(
-                        "nonexistent".into(),
-                        source_file.clone(),
-                    )])),
-                    dest: FileDefinition::Single(dest_file.clone()),
                     raw: true,
                     optional: true,
+                    ..fixture::File::new(
+                        FileDefinition::Multiple(HashMap::from_iter(vec![(
+                            "nonexistent".into(),
+                            source_file.clone(),
+                        )])),
+                        FileDefinition::Single(dest_file.clone()),
+                    )
                 }],
                 root: false,
+                allow_commands: false,
+                strict: false,
                 secrets: Default::default(),
             }),
         };
 
-        apply_fixtures(vec![fixture], false, true).unwrap();
+        apply_fixtures(vec![fixture], false, true, Escalation::None, false).unwrap();
 
         assert!(!dest_file.exists());
     }
@@ -364,22 +1019,28 @@ mod tests {
             name: "test-fixture".into(),
             include_for: None,
             exclude_for: None,
+            vars: Default::default(),
+            dir: Default::default(),
             fixture_type: FixtureType::Files(fixture::FilesSetup {
                 files: vec![fixture::File {
-                    src: FileDefinition::Single(source_file.clone()),
-                    dest: FileDefinition::Multiple(HashMap::from_iter(vec![(
-                        "nonexistent".into(),
-                        dest_file.clone(),
-                    )])),
                     raw: true,
                     optional: true,
+                    ..fixture::File::new(
+                        FileDefinition::Single(source_file.clone()),
+                        FileDefinition::Multiple(HashMap::from_iter(vec![(
+                            "nonexistent".into(),
+                            dest_file.clone(),
+                        )])),
+                    )
                 }],
                 root: false,
+                allow_commands: false,
+                strict: false,
                 secrets: Default::default(),
             }),
         };
 
-        apply_fixtures(vec![fixture], false, true).unwrap();
+        apply_fixtures(vec![fixture], false, true, Escalation::None, false).unwrap();
 
         assert!(!dest_file.exists());
     }
@@ -391,19 +1052,17 @@ mod tests {
         let src_path = test_dir.path().join("source.conf");
         let dest_path = test_dir.path().join("nested/dest.conf");
 
-        let file = File {
-            src: FileDefinition::Single(src_path.clone()),
-            dest: FileDefinition::Single(dest_path.clone()),
-            raw: false,
-            optional: false,
-        };
+        let file = File::new(
+            FileDefinition::Single(src_path.clone()),
+            FileDefinition::Single(dest_path.clone()),
+        );
 
         std::fs::write(&src_path, "Hello, World!").unwrap();
 
         assert!(!dest_path.parent().unwrap().exists());
         assert!(!dest_path.exists());
 
-        apply_file(&file, test_dir.path(), false, &HashMap::new(), false, true).unwrap();
+        apply_file(&file, &options(test_dir.path()), &renderer(), &mut batch()).unwrap();
 
         assert!(dest_path.parent().unwrap().exists());
         assert!(dest_path.exists());
@@ -416,12 +1075,10 @@ mod tests {
         let src_path = test_dir.path().join("source.conf");
         let dest_path = test_dir.path().join("dest.conf");
 
-        let file = File {
-            src: FileDefinition::Single(src_path.clone()),
-            dest: FileDefinition::Single(dest_path.clone()),
-            raw: false,
-            optional: false,
-        };
+        let file = File::new(
+            FileDefinition::Single(src_path.clone()),
+            FileDefinition::Single(dest_path.clone()),
+        );
 
         let mode = 0o600;
 
@@ -432,12 +1089,227 @@ mod tests {
         let mut src_file = open_options.open(&src_path).unwrap();
         src_file.write_all(b"Hello, World!").unwrap();
 
-        apply_file(&file, test_dir.path(), false, &HashMap::new(), false, true).unwrap();
+        apply_file(&file, &options(test_dir.path()), &renderer(), &mut batch()).unwrap();
 
         let dest_metadata = std::fs::metadata(&dest_path).unwrap();
         assert_eq!(dest_metadata.mode() & 0o777, mode);
     }
 
+    #[test]
+    fn test_apply_block() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let src_path = test_dir.path().join("hosts");
+        let dest_path = test_dir.path().join("etc-hosts");
+        std::fs::write(&src_path, "10.0.0.2 nas\n").unwrap();
+        std::fs::write(&dest_path, "127.0.0.1 localhost\n").unwrap();
+
+        let file = File {
+            strategy: Strategy::Block,
+            ..File::new(
+                FileDefinition::Single(src_path),
+                FileDefinition::Single(dest_path.clone()),
+            )
+        };
+
+        apply_file(&file, &options(test_dir.path()), &renderer(), &mut batch()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&dest_path).unwrap(),
+            "127.0.0.1 localhost\n# BEGIN spaceconf:test\n10.0.0.2 nas\n# END spaceconf:test\n"
+        );
+
+        let revert = ApplyOptions {
+            revert: true,
+            ..options(test_dir.path())
+        };
+        apply_file(&file, &revert, &renderer(), &mut batch()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&dest_path).unwrap(),
+            "127.0.0.1 localhost\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_absent() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let backup_dir = test_dir.path().join("backup");
+        let dest_path = test_dir.path().join("autostart/old.desktop");
+        std::fs::create_dir_all(dest_path.parent().unwrap()).unwrap();
+        std::fs::write(&dest_path, "[Desktop Entry]\n").unwrap();
+
+        let file = File {
+            state: State::Absent,
+            ..File::new(
+                FileDefinition::default(),
+                FileDefinition::Single(dest_path.clone()),
+            )
+        };
+        let apply = ApplyOptions {
+            no_backup: false,
+            ..options(&backup_dir)
+        };
+
+        apply_file(&file, &apply, &renderer(), &mut batch()).unwrap();
+        assert!(!dest_path.exists());
+
+        // already absent
+        apply_file(&file, &apply, &renderer(), &mut batch()).unwrap();
+
+        let revert = ApplyOptions {
+            revert: true,
+            ..apply
+        };
+        apply_file(&file, &revert, &renderer(), &mut batch()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&dest_path).unwrap(),
+            "[Desktop Entry]\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_absent_symlink() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let backup_dir = test_dir.path().join("backup");
+        let dest_path = test_dir.path().join(".bash_profile");
+        std::os::unix::fs::symlink("dotfiles/profile", &dest_path).unwrap();
+
+        let file = File {
+            state: State::Absent,
+            ..File::new(
+                FileDefinition::default(),
+                FileDefinition::Single(dest_path.clone()),
+            )
+        };
+        let apply = ApplyOptions {
+            no_backup: false,
+            ..options(&backup_dir)
+        };
+
+        // dangling, but still backed up and removed
+        apply_file(&file, &apply, &renderer(), &mut batch()).unwrap();
+        assert!(dest_path.symlink_metadata().is_err());
+
+        let revert = ApplyOptions {
+            revert: true,
+            ..apply
+        };
+        apply_file(&file, &revert, &renderer(), &mut batch()).unwrap();
+        assert_eq!(
+            std::fs::read_link(&dest_path).unwrap(),
+            PathBuf::from("dotfiles/profile")
+        );
+    }
+
+    #[test]
+    fn test_apply_directory() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let backup_dir = test_dir.path().join("backup");
+        let ssh = test_dir.path().join(".ssh");
+        std::fs::create_dir_all(ssh.join("keys")).unwrap();
+        std::fs::write(ssh.join("config"), "Host *\n").unwrap();
+        std::fs::write(ssh.join("keys/old"), "old key").unwrap();
+        std::fs::set_permissions(&ssh, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // backed up for some other entry, not by the purge
+        let unrelated = backup_dir.join(ssh.join("unrelated").strip_prefix("/").unwrap());
+        std::fs::create_dir_all(unrelated.parent().unwrap()).unwrap();
+        std::fs::write(&unrelated, "unrelated").unwrap();
+
+        let file = File {
+            mode: Some(0o700),
+            state: State::Directory,
+            purge: true,
+            ..File::new(
+                FileDefinition::default(),
+                FileDefinition::Single(ssh.clone()),
+            )
+        };
+        let managed = vec![ssh.clone(), ssh.join("config")];
+        let apply = ApplyOptions {
+            no_backup: false,
+            managed: &managed,
+            ..options(&backup_dir)
+        };
+
+        apply_file(&file, &apply, &renderer(), &mut batch()).unwrap();
+        assert_eq!(ssh.metadata().unwrap().mode() & 0o7777, 0o700);
+        assert!(ssh.join("config").exists());
+        assert!(!ssh.join("keys").exists());
+
+        let revert = ApplyOptions {
+            revert: true,
+            ..apply
+        };
+        apply_file(&file, &revert, &renderer(), &mut batch()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(ssh.join("keys/old")).unwrap(),
+            "old key"
+        );
+        assert_eq!(ssh.metadata().unwrap().mode() & 0o7777, 0o755);
+        assert!(!ssh.join("unrelated").exists());
+
+        // nothing left to revert
+        apply_file(&file, &revert, &renderer(), &mut batch()).unwrap();
+        assert!(ssh.exists());
+    }
+
+    #[test]
+    fn test_purge_root_directory() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let backup_dir = test_dir.path().join("backup");
+        let conf_dir = test_dir.path().join("conf.d");
+        std::fs::create_dir_all(conf_dir.join("old")).unwrap();
+        std::fs::write(conf_dir.join("old/extra.conf"), "extra").unwrap();
+
+        let file = File {
+            state: State::Directory,
+            purge: true,
+            ..File::new(
+                FileDefinition::default(),
+                FileDefinition::Single(conf_dir.clone()),
+            )
+        };
+        let managed = vec![conf_dir.clone()];
+        let apply = ApplyOptions {
+            root: true,
+            no_backup: false,
+            managed: &managed,
+            ..options(&backup_dir)
+        };
+
+        let mut batch = batch();
+        apply_file(&file, &apply, &renderer(), &mut batch).unwrap();
+        // backed up and removed as root, once the batch runs
+        let backup = get_backup_filename(&backup_dir, &conf_dir.join("old/extra.conf"));
+        assert!(conf_dir.join("old").exists());
+        assert!(!backup.exists());
+
+        batch.run().unwrap();
+        assert!(!conf_dir.join("old").exists());
+        assert_eq!(std::fs::read_to_string(backup).unwrap(), "extra");
+    }
+
+    #[test]
+    fn test_check_attributes() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let path = test_dir.path().join("file");
+        std::fs::write(&path, "").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let metadata = path.metadata().unwrap();
+
+        let mut attributes = Attributes {
+            mode: 0o640,
+            owner: Some(metadata.uid().to_string()),
+            group: Some(metadata.gid().to_string()),
+        };
+        assert!(check_attributes(&attributes, &path));
+
+        attributes.mode = 0o644;
+        assert!(!check_attributes(&attributes, &path));
+
+        attributes.mode = 0o640;
+        attributes.owner = Some((metadata.uid() + 1).to_string());
+        assert!(!check_attributes(&attributes, &path));
+    }
+
     #[test]
     fn test_backup_file() {
         let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
@@ -452,7 +1324,7 @@ mod tests {
 
         assert!(!backup_filename.exists());
 
-        backup_file(&backup_dir, &file);
+        backup_file(&backup_dir, &file).unwrap();
 
         assert!(backup_filename.exists());
     }
@@ -477,7 +1349,7 @@ mod tests {
 
         assert_eq!(pre_restore_content, "Hello, World!");
 
-        restore_file(&backup_dir, &restored_file, false).unwrap();
+        restore_file(&backup_dir, &restored_file, None).unwrap();
 
         assert!(restored_file.exists());
 
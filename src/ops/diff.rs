@@ -1,56 +1,206 @@
+use std::{os::unix::fs::MetadataExt, path::PathBuf};
+
 use log::error;
 use similar::TextDiff;
 
 use crate::{
-    fixture::{Fixture, FixtureType},
-    template,
+    archive::ArchiveState,
+    command::{self, RunOnce},
+    fixture::{
+        ArchiveSetup, CommandSetup, File, Fixture, FixtureType, GitConfigSetup, PackageLists, State,
+    },
+    gitconfig,
+    host::Host,
+    ops::render,
+    packages::{PackageManager, System},
+    secrets, strategy,
+    template::Renderer,
 };
 
-pub fn diff_fixtures(fixtures: Vec<Fixture>) {
+pub fn diff_fixtures(fixtures: Vec<Fixture>) -> std::io::Result<()> {
+    let managed = render::all_managed_paths(&fixtures, &Host::current());
+    let mut failures = Vec::new();
     for fixture in fixtures {
         if fixture.skip() {
             continue;
         }
-        let FixtureType::Files(setup) = fixture.fixture_type else {
-            continue;
-        };
-
-        for file in setup.files {
-            let Some(src) = file.src.clone().resolve() else {
+        let setup = match &fixture.fixture_type {
+            FixtureType::Files(setup) => setup,
+            FixtureType::Packages(setup) => {
+                if let Some(lists) = setup.packages.select_for(&Host::current()) {
+                    if let Err(e) = diff_packages(lists) {
+                        failures.push((fixture.name.clone(), e));
+                    }
+                }
                 continue;
-            };
-            let Some(dest) = file.dest.clone().resolve() else {
+            }
+            FixtureType::Archive(setup) => {
+                if let Err(e) = diff_archive(&fixture, setup) {
+                    failures.push((fixture.name.clone(), e));
+                }
                 continue;
-            };
-
-            let input = std::fs::read_to_string(&src)
-                .inspect_err(|_| error!("failed to read source file: {}", &src.to_string_lossy()))
-                .unwrap();
-            let input = template::render(&input, &setup.secrets).unwrap();
-
-            let output = if dest.exists() {
-                std::fs::read_to_string(&dest)
-                    .inspect_err(|_| {
-                        error!(
-                            "failed to read destination file: {}",
-                            &dest.to_string_lossy()
-                        )
-                    })
-                    .unwrap()
-            } else {
-                String::new()
-            };
-
-            let diff = TextDiff::from_lines(&input, &output);
-            if diff.ratio() == 1.0 {
+            }
+            FixtureType::Command(setup) => {
+                if let Err(e) = diff_command(&fixture, setup) {
+                    failures.push((fixture.name.clone(), e));
+                }
                 continue;
             }
+            FixtureType::Gitconfig(setup) => {
+                if let Err(e) = diff_gitconfig(&fixture, setup) {
+                    failures.push((fixture.name.clone(), e));
+                }
+                continue;
+            }
+            _ => continue,
+        };
+
+        let renderer = match render::renderer(&fixture, setup, &Host::current()) {
+            Ok(renderer) => renderer,
+            Err(e) => {
+                failures.push((fixture.name.clone(), e));
+                continue;
+            }
+        };
+        for file in &setup.files {
+            if let Err(e) = diff_file(file, &fixture.name, &renderer, &managed) {
+                failures.push((fixture.name.clone(), e));
+            }
+        }
+    }
+
+    render::report_failures(&failures)
+}
+
+/// Print the packages that would be installed
+fn diff_packages(lists: &PackageLists) -> std::io::Result<()> {
+    for (manager, packages) in lists {
+        let missing = System(*manager).missing(packages)?;
+        if !missing.is_empty() {
+            println!("{}: would install {}", manager, missing.join(", "));
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the archive if it isn't extracted yet
+fn diff_archive(fixture: &Fixture, setup: &ArchiveSetup) -> std::io::Result<()> {
+    let dest = Renderer::new(&Host::current(), &fixture.vars, false)
+        .render_path(&setup.dest)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let state = ArchiveState::load(&ArchiveState::state_file())?;
+    if !state.is_extracted(&dest, &setup.sha256) {
+        println!("{}: would extract {}", dest.to_string_lossy(), setup.url);
+    }
+
+    Ok(())
+}
+
+/// Print the command if its guards would let it run
+fn diff_command(fixture: &Fixture, setup: &CommandSetup) -> std::io::Result<()> {
+    let renderer = Renderer::new(&Host::current(), &fixture.vars, false);
+    let rendered = command::render(setup, &renderer)?;
+    let run_once = RunOnce::load(&RunOnce::state_file())?;
+    if command::skip_reason(&fixture.name, &rendered, &run_once)?.is_none() {
+        println!("{}: would run `{}`", fixture.name, rendered.command);
+    }
+
+    Ok(())
+}
+
+/// Print each key whose values would change
+fn diff_gitconfig(fixture: &Fixture, setup: &GitConfigSetup) -> std::io::Result<()> {
+    let host = Host::current();
+    let renderer = Renderer::new(&host, &fixture.vars, false);
+    let path = renderer
+        .render_path(&setup.path)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    for drift in gitconfig::drift(setup, &path, &host, &renderer)? {
+        println!("{}: {}", path.to_string_lossy(), drift);
+    }
+
+    Ok(())
+}
+
+/// Print what would change about a directory entry
+fn diff_directory(file: &File, renderer: &Renderer, managed: &[PathBuf]) -> std::io::Result<()> {
+    let Some(dest) = render::resolve_dest(file, &Host::current(), renderer)? else {
+        return Ok(());
+    };
+    if !dest.is_dir() {
+        println!("{}: directory will be created", dest.to_string_lossy());
+        return Ok(());
+    }
+
+    let current = dest.metadata()?.mode() & 0o7777;
+    if let Some(mode) = file.mode.filter(|mode| *mode != current) {
+        println!(
+            "{}: mode will change from {:04o} to {:04o}",
+            dest.to_string_lossy(),
+            current,
+            mode
+        );
+    }
+    if file.purge {
+        for path in render::unmanaged(&dest, managed)? {
+            println!("{}: will be purged", path.to_string_lossy());
+        }
+    }
 
-            let mut unified = diff.unified_diff();
-            unified.header(&src.to_string_lossy(), &dest.to_string_lossy());
+    Ok(())
+}
 
-            let stdout = std::io::stdout();
-            unified.to_writer(stdout.lock()).unwrap();
+fn diff_file(
+    file: &File,
+    fixture: &str,
+    renderer: &Renderer,
+    managed: &[PathBuf],
+) -> std::io::Result<()> {
+    if file.state == State::Directory {
+        return diff_directory(file, renderer, managed);
+    }
+    if file.state == State::Absent {
+        if let Some(dest) = render::resolve_dest(file, &Host::current(), renderer)? {
+            if dest.symlink_metadata().is_ok() {
+                println!("{}: will be removed", dest.to_string_lossy());
+            }
         }
+        return Ok(());
     }
+
+    let Some(src) = file.src.clone().resolve() else {
+        return Ok(());
+    };
+    let Some(dest) = render::resolve_dest(file, &Host::current(), renderer)? else {
+        return Ok(());
+    };
+
+    let rendered = render::render_source(file, &src, renderer)?;
+
+    let current = if dest.exists() {
+        Some(std::fs::read_to_string(&dest).inspect_err(|_| {
+            error!(
+                "failed to read destination file: {}",
+                &dest.to_string_lossy()
+            )
+        })?)
+    } else {
+        None
+    };
+    if let Some(status) = strategy::status(file, &rendered, current.as_deref())? {
+        println!("{}: {}", dest.to_string_lossy(), status);
+    }
+    let (output, input) = strategy::managed(file, fixture, &dest, &rendered, current.as_deref())?;
+
+    let diff = TextDiff::from_lines(&input, &output);
+    if diff.ratio() == 1.0 {
+        return Ok(());
+    }
+
+    let mut unified = diff.unified_diff();
+    unified.header(&src.to_string_lossy(), &dest.to_string_lossy());
+
+    print!("{}", secrets::redact(&unified.to_string()));
+    Ok(())
 }
@@ -0,0 +1,322 @@
+use std::{
+    io::{BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use log::error;
+
+use crate::{
+    fixture::{Fixture, FixtureType},
+    git,
+    host::Host,
+    ops::load::load_fixtures_for,
+    secrets::SECRETS_FILE,
+};
+
+/// Added to `.gitignore` so plaintext secrets files are never committed
+pub const IGNORE_RULE: &str = SECRETS_FILE;
+
+/// A plaintext secrets file that could end up in the repository's history
+#[derive(Clone, Debug, PartialEq)]
+pub enum SecretsProblem {
+    /// Already committed, ignoring it is not enough
+    Tracked(PathBuf),
+    /// Not committed yet, but `git add` would pick it up
+    NotIgnored(PathBuf),
+}
+
+impl std::fmt::Display for SecretsProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretsProblem::Tracked(path) => write!(
+                f,
+                "{} is tracked by git, remove it with `git rm --cached {}` and encrypt it with \
+                 `spaceconf secrets edit`",
+                path.display(),
+                path.display()
+            ),
+            SecretsProblem::NotIgnored(path) => {
+                write!(f, "{} is not covered by .gitignore", path.display())
+            }
+        }
+    }
+}
+
+/// A string in a source file that looks like a key or token
+#[derive(Clone, Debug, PartialEq)]
+pub struct SuspiciousString {
+    pub path: PathBuf,
+    pub line: usize,
+    pub preview: String,
+}
+
+impl std::fmt::Display for SuspiciousString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: {} looks like a secret, consider moving it to secrets.json.age",
+            self.path.display(),
+            self.line,
+            self.preview
+        )
+    }
+}
+
+/// Find plaintext secrets files in the repository that are tracked by git or not ignored.
+/// Nothing is reported if the repository isn't a git repository.
+pub fn plaintext_secrets(repo_dir: &Path) -> Vec<SecretsProblem> {
+    if !git::is_repository(repo_dir) {
+        return vec![];
+    }
+
+    let mut files = Vec::new();
+    find_secrets_files(repo_dir, repo_dir, &mut files);
+    let tracked = git::tracked_files(repo_dir);
+
+    files
+        .into_iter()
+        .filter_map(|file| {
+            if tracked.contains(&file) {
+                Some(SecretsProblem::Tracked(file))
+            } else if !git::is_ignored(repo_dir, &file) {
+                Some(SecretsProblem::NotIgnored(file))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn find_secrets_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if entry.file_name() == ".git" {
+            continue;
+        }
+
+        if path.is_dir() {
+            find_secrets_files(root, &path, files);
+        } else if entry.file_name() == SECRETS_FILE {
+            files.push(path.strip_prefix(root).unwrap().to_path_buf());
+        }
+    }
+}
+
+/// Add the ignore rule for plaintext secrets to the repository's `.gitignore`
+pub fn add_ignore_rules(repo_dir: &Path) -> std::io::Result<()> {
+    let gitignore = repo_dir.join(".gitignore");
+    let content = if gitignore.exists() {
+        std::fs::read_to_string(&gitignore)?
+    } else {
+        String::new()
+    };
+
+    if content.lines().any(|line| line.trim() == IGNORE_RULE) {
+        return Ok(());
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&gitignore)
+        .inspect_err(|_| error!("failed to open {}", &gitignore.to_string_lossy()))?;
+    if !content.is_empty() && !content.ends_with('\n') {
+        writeln!(file)?;
+    }
+    writeln!(file, "{}", IGNORE_RULE)
+}
+
+/// Scan the sources of files fixtures for long, random looking strings, which are usually keys
+/// or tokens that should be secrets instead. Raw files are scanned as well.
+pub fn scan_sources(fixtures: &[Fixture]) -> Vec<SuspiciousString> {
+    let mut found = Vec::new();
+    for fixture in fixtures {
+        let FixtureType::Files(setup) = &fixture.fixture_type else {
+            continue;
+        };
+
+        for file in &setup.files {
+            let Some(src) = file.src.clone().resolve() else {
+                continue;
+            };
+            let Ok(content) = std::fs::read_to_string(&src) else {
+                continue;
+            };
+
+            found.extend(scan(&src, &content));
+        }
+    }
+
+    found
+}
+
+const MIN_TOKEN_LENGTH: usize = 20;
+const MIN_ENTROPY: f64 = 4.0;
+
+fn scan(path: &Path, content: &str) -> Vec<SuspiciousString> {
+    let is_token = |c: char| c.is_ascii_alphanumeric() || "+/=_-".contains(c);
+
+    let mut found = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        for token in line.split(|c: char| !is_token(c)) {
+            if token.len() >= MIN_TOKEN_LENGTH && entropy(token) >= MIN_ENTROPY {
+                found.push(SuspiciousString {
+                    path: path.to_path_buf(),
+                    line: index + 1,
+                    preview: format!("{}...", &token[..4]),
+                });
+            }
+        }
+    }
+
+    found
+}
+
+/// Shannon entropy in bits per character
+fn entropy(token: &str) -> f64 {
+    let mut counts = [0usize; 256];
+    for byte in token.bytes() {
+        counts[byte as usize] += 1;
+    }
+
+    let len = token.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Check the repository for plaintext secrets that could be committed, offering to add ignore
+/// rules, and optionally scan sources for strings that look like keys.
+///
+/// Returns whether no problems were found.
+pub fn doctor(repo_dir: &Path, fix: bool, scan_entropy: bool) -> std::io::Result<bool> {
+    let problems = plaintext_secrets(repo_dir);
+    for problem in &problems {
+        println!("plaintext secrets: {}", problem);
+    }
+
+    let unignored = problems
+        .iter()
+        .any(|problem| matches!(problem, SecretsProblem::NotIgnored(_)));
+    if unignored && (fix || confirm("Add secrets.json to .gitignore?")?) {
+        add_ignore_rules(repo_dir)?;
+        println!("Added {} to .gitignore", IGNORE_RULE);
+    }
+
+    let mut healthy = problems.is_empty();
+    if scan_entropy {
+        let fixtures = load_fixtures_for(repo_dir.to_path_buf(), vec![], &Host::current())?;
+        let found = scan_sources(&fixtures);
+        for suspicious in &found {
+            println!("{}", suspicious);
+        }
+        healthy &= found.is_empty();
+    }
+
+    if healthy {
+        println!("No problems found");
+    }
+
+    Ok(healthy)
+}
+
+fn confirm(question: &str) -> std::io::Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_plaintext_secrets() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let repo_dir = test_dir.path();
+        git(repo_dir, &["init", "-q"]);
+
+        std::fs::create_dir_all(repo_dir.join("weather")).unwrap();
+        std::fs::create_dir_all(repo_dir.join("git")).unwrap();
+        std::fs::write(repo_dir.join("weather/secrets.json"), "{}").unwrap();
+        std::fs::write(repo_dir.join("git/secrets.json"), "{}").unwrap();
+        git(repo_dir, &["add", "git/secrets.json"]);
+
+        let mut problems = plaintext_secrets(repo_dir);
+        problems.sort_by_key(|problem| problem.to_string());
+        assert_eq!(
+            problems,
+            vec![
+                SecretsProblem::Tracked("git/secrets.json".into()),
+                SecretsProblem::NotIgnored("weather/secrets.json".into()),
+            ]
+        );
+
+        add_ignore_rules(repo_dir).unwrap();
+        add_ignore_rules(repo_dir).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(repo_dir.join(".gitignore")).unwrap(),
+            "secrets.json\n"
+        );
+        assert_eq!(
+            plaintext_secrets(repo_dir),
+            vec![SecretsProblem::Tracked("git/secrets.json".into())]
+        );
+    }
+
+    #[test]
+    fn test_not_a_repository() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        std::fs::write(test_dir.path().join("secrets.json"), "{}").unwrap();
+
+        assert!(plaintext_secrets(test_dir.path()).is_empty());
+    }
+
+    #[test]
+    fn test_scan() {
+        let content = "\
+[weather]
+location = \"Amsterdam, Netherlands\"
+api_key = \"sk_9fQ2xL7pRz4Tb8VmK1wYc3Nd\"
+path = /usr/share/applications/weather
+";
+
+        let found = scan(Path::new("weather.conf"), content);
+        assert_eq!(
+            found,
+            vec![SuspiciousString {
+                path: "weather.conf".into(),
+                line: 3,
+                preview: "sk_9...".into(),
+            }]
+        );
+    }
+}
@@ -1,10 +1,29 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use log::error;
+use log::{error, warn};
 
-use crate::fixture::{Fixture, FixtureType};
+use crate::{
+    fixture::{Fixture, FixtureType},
+    host::Host,
+    ops::doctor::{self, SecretsProblem},
+    secrets,
+    vars::RepoVars,
+};
 
 pub fn load_fixtures(dir: PathBuf, names: Vec<String>) -> std::io::Result<Vec<Fixture>> {
+    load_fixtures_for(dir, names, &Host::current())
+}
+
+/// Load fixtures, validating them against the given host rather than the current machine
+pub fn load_fixtures_for(
+    dir: PathBuf,
+    names: Vec<String>,
+    host: &Host,
+) -> std::io::Result<Vec<Fixture>> {
+    warn_plaintext_secrets(&dir);
+    let repo_vars = RepoVars::load(&dir)?;
+    let key_file = secrets::key_file();
+    let repo_secrets = secrets::load(&dir, &key_file)?;
     let dir_entries = std::fs::read_dir(dir)?;
     let fixture_dirs = dir_entries
         .filter_map(|entry| entry.ok())
@@ -28,18 +47,32 @@ pub fn load_fixtures(dir: PathBuf, names: Vec<String>) -> std::io::Result<Vec<Fi
     let fixtures = fixture_dirs
         .map(|fixture_dir| {
             let fixture_file = fixture_dir.join("fixture.json");
-            let secret_file = fixture_dir.join("secrets.json");
             let fixture = std::fs::read_to_string(fixture_file).unwrap();
             let mut fixture: Fixture = serde_json::from_str(&fixture).unwrap();
+            fixture.vars = repo_vars.resolve(&fixture.vars, host);
+            fixture.dir = fixture_dir.clone();
 
             // resolve relative paths to absolute paths and load secrets
             if let FixtureType::Files(ref mut setup) = &mut fixture.fixture_type {
                 for file in &mut setup.files {
                     file.src = file.src.clone().expand(&fixture_dir);
                 }
-                if secret_file.exists() {
-                    let secrets = std::fs::read_to_string(secret_file).unwrap();
-                    setup.secrets = serde_json::from_str(&secrets).unwrap();
+                // fixture secrets override the ones shared by the whole repository
+                setup.secrets = repo_secrets.clone();
+                setup
+                    .secrets
+                    .extend(secrets::load(&fixture_dir, &key_file)?);
+            }
+
+            if let FixtureType::Systemd(ref mut setup) = &mut fixture.fixture_type {
+                for unit in &mut setup.units {
+                    unit.src = fixture_dir.join(&unit.src);
+                }
+            }
+
+            if let FixtureType::Archive(ref mut setup) = &mut fixture.fixture_type {
+                if !setup.url.contains("://") {
+                    setup.url = fixture_dir.join(&setup.url).to_string_lossy().to_string();
                 }
             }
 
@@ -51,14 +84,14 @@ pub fn load_fixtures(dir: PathBuf, names: Vec<String>) -> std::io::Result<Vec<Fi
                     .to_string();
             }
 
-            let _ = fixture.validate().inspect_err(|e| {
+            let _ = fixture.validate_for(host).inspect_err(|e| {
                 error!("Invalid fixture: {}", e);
                 std::process::exit(1);
             });
 
-            fixture
+            Ok(fixture)
         })
-        .collect();
+        .collect::<std::io::Result<Vec<_>>>()?;
 
     // TODO: process fixtures
     // assign the name if it's not present
@@ -67,6 +100,20 @@ pub fn load_fixtures(dir: PathBuf, names: Vec<String>) -> std::io::Result<Vec<Fi
     Ok(fixtures)
 }
 
+/// Warn about plaintext secrets files that could be committed, with the same check as the
+/// doctor, returning them
+fn warn_plaintext_secrets(dir: &Path) -> Vec<SecretsProblem> {
+    let problems = doctor::plaintext_secrets(dir);
+    for problem in &problems {
+        warn!("plaintext secrets: {}", problem);
+    }
+    if !problems.is_empty() {
+        warn!("run `spaceconf doctor` to fix this");
+    }
+
+    problems
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
@@ -87,14 +134,16 @@ mod tests {
             name: "test-fixture".into(),
             include_for: None,
             exclude_for: None,
+            vars: Default::default(),
+            dir: Default::default(),
             fixture_type: FixtureType::Files(FilesSetup {
-                files: vec![File {
-                    src: FileDefinition::Single("source.conf".into()),
-                    dest: FileDefinition::Single("/etc/dest.conf".into()),
-                    raw: false,
-                    optional: false,
-                }],
+                files: vec![File::new(
+                    FileDefinition::Single("source.conf".into()),
+                    FileDefinition::Single("/etc/dest.conf".into()),
+                )],
                 root: false,
+                allow_commands: false,
+                strict: false,
                 secrets: Default::default(),
             }),
         };
@@ -106,6 +155,7 @@ mod tests {
         assert!(matches!(fixtures[0].fixture_type, FixtureType::Files(_)));
 
         assert_eq!(fixtures[0].name, "test-fixture".to_string());
+        assert_eq!(fixtures[0].dir, fixture_dir);
 
         let setup = match &fixtures[0].fixture_type {
             FixtureType::Files(setup) => setup,
@@ -140,14 +190,16 @@ mod tests {
             name: "test-fixture".into(),
             include_for: None,
             exclude_for: None,
+            vars: Default::default(),
+            dir: Default::default(),
             fixture_type: FixtureType::Files(FilesSetup {
-                files: vec![File {
-                    src: FileDefinition::Single("source.conf".into()),
-                    dest: FileDefinition::Single("/etc/dest.conf".into()),
-                    raw: false,
-                    optional: false,
-                }],
+                files: vec![File::new(
+                    FileDefinition::Single("source.conf".into()),
+                    FileDefinition::Single("/etc/dest.conf".into()),
+                )],
                 root: false,
+                allow_commands: false,
+                strict: false,
                 secrets: Default::default(),
             }),
         };
@@ -193,14 +245,16 @@ mod tests {
             name: "test-fixture".into(),
             include_for: None,
             exclude_for: None,
+            vars: Default::default(),
+            dir: Default::default(),
             fixture_type: FixtureType::Files(FilesSetup {
-                files: vec![File {
-                    src: FileDefinition::Single("source.conf".into()),
-                    dest: FileDefinition::Single("/etc/dest.conf".into()),
-                    raw: false,
-                    optional: false,
-                }],
+                files: vec![File::new(
+                    FileDefinition::Single("source.conf".into()),
+                    FileDefinition::Single("/etc/dest.conf".into()),
+                )],
                 root: false,
+                allow_commands: false,
+                strict: false,
                 secrets: Default::default(),
             }),
         };
@@ -210,6 +264,11 @@ mod tests {
         let secrets: HashMap<String, String> =
             HashMap::from_iter(vec![("key".into(), "value".into())]);
         std::fs::write(secrets_file, serde_json::to_string(&secrets).unwrap()).unwrap();
+        std::fs::write(
+            test_dir.path().join("secrets.json"),
+            r#"{"key": "repo value", "GITHUB_TOKEN": "ghp_12345"}"#,
+        )
+        .unwrap();
 
         let fixtures = load_fixtures(test_dir.path().to_path_buf(), vec![]).unwrap();
 
@@ -237,6 +296,57 @@ mod tests {
 
         assert!(!setup.root);
         assert_eq!(setup.secrets.get("key"), Some(&"value".into()));
+        assert_eq!(setup.secrets.get("GITHUB_TOKEN"), Some(&"ghp_12345".into()));
+    }
+
+    #[test]
+    fn test_warn_plaintext_secrets() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let status = std::process::Command::new("git")
+            .args(["init", "-q"])
+            .current_dir(test_dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let fixture_dir = test_dir.path().join("test-fixture");
+        std::fs::create_dir(&fixture_dir).unwrap();
+        std::fs::write(
+            fixture_dir.join("fixture.json"),
+            r#"{"type": "files", "files": [{"src": "source.conf", "dest": "/etc/dest.conf", "optional": true}]}"#,
+        )
+        .unwrap();
+        std::fs::write(fixture_dir.join("secrets.json"), r#"{"key": "value"}"#).unwrap();
+
+        assert_eq!(
+            warn_plaintext_secrets(test_dir.path()),
+            vec![SecretsProblem::NotIgnored(
+                "test-fixture/secrets.json".into()
+            )]
+        );
+        // only a warning, the fixtures still load
+        assert_eq!(
+            load_fixtures(test_dir.path().to_path_buf(), vec![])
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_get_fixtures_with_invalid_secrets() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+
+        let fixture_dir = test_dir.path().join("test-fixture");
+        std::fs::create_dir(&fixture_dir).unwrap();
+        std::fs::write(
+            fixture_dir.join("fixture.json"),
+            r#"{"type": "files", "files": []}"#,
+        )
+        .unwrap();
+        std::fs::write(fixture_dir.join("secrets.json"), "{not json").unwrap();
+
+        assert!(load_fixtures(test_dir.path().to_path_buf(), vec![]).is_err());
     }
 
     #[test]
@@ -251,14 +361,16 @@ mod tests {
             name: "".into(),
             include_for: None,
             exclude_for: None,
+            vars: Default::default(),
+            dir: Default::default(),
             fixture_type: FixtureType::Files(FilesSetup {
-                files: vec![File {
-                    src: FileDefinition::Single("source.conf".into()),
-                    dest: FileDefinition::Single("/etc/dest.conf".into()),
-                    raw: false,
-                    optional: false,
-                }],
+                files: vec![File::new(
+                    FileDefinition::Single("source.conf".into()),
+                    FileDefinition::Single("/etc/dest.conf".into()),
+                )],
                 root: false,
+                allow_commands: false,
+                strict: false,
                 secrets: Default::default(),
             }),
         };
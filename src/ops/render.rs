@@ -0,0 +1,548 @@
+use std::{
+    collections::BTreeSet,
+    io::Write,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+
+use log::error;
+use resolve_path::PathResolveExt;
+
+use crate::{
+    archive::ArchiveState,
+    fixture::{File, FilesSetup, Fixture, FixtureType, State},
+    host::Host,
+    secrets, systemd,
+    template::{self, Renderer},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderedFile {
+    pub src: PathBuf,
+    pub dest: PathBuf,
+    pub content: String,
+}
+
+/// Render every file of a fixture as it would be written for the given host
+pub fn render_fixture(fixture: &Fixture, host: &Host) -> Vec<std::io::Result<RenderedFile>> {
+    let FixtureType::Files(setup) = &fixture.fixture_type else {
+        return vec![];
+    };
+
+    let renderer = match renderer(fixture, setup, host) {
+        Ok(renderer) => renderer,
+        Err(e) => return vec![Err(e)],
+    };
+
+    let mut rendered = Vec::new();
+    for file in &setup.files {
+        let Some(src) = file.src.clone().resolve_for(host) else {
+            continue;
+        };
+        let dest = match resolve_dest(file, host, &renderer) {
+            Ok(Some(dest)) => dest,
+            Ok(None) => continue,
+            Err(e) => {
+                rendered.push(Err(e));
+                continue;
+            }
+        };
+
+        rendered.push(
+            render_source(file, &src, &renderer).map(|content| RenderedFile { src, dest, content }),
+        );
+    }
+
+    rendered
+}
+
+/// Read a source file and render it, unless it is raw
+pub(crate) fn render_source(
+    file: &File,
+    src: &Path,
+    renderer: &Renderer,
+) -> std::io::Result<String> {
+    let input = std::fs::read_to_string(src)
+        .inspect_err(|_| error!("failed to read source file: {}", &src.to_string_lossy()))?;
+
+    if file.raw {
+        return Ok(input);
+    }
+
+    renderer
+        .render_file(src, &input)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Print every failure from a run, returning an error if there were any
+pub(crate) fn report_failures(failures: &[(String, std::io::Error)]) -> std::io::Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!();
+    eprintln!("{} file(s) failed:", failures.len());
+    for (fixture, e) in failures {
+        eprintln!("  {}: {}", fixture, secrets::redact(&e.to_string()));
+    }
+
+    Err(std::io::Error::other(format!(
+        "{} file(s) failed",
+        failures.len()
+    )))
+}
+
+/// Set up the template renderer for a fixture's files
+pub(crate) fn renderer(
+    fixture: &Fixture,
+    setup: &FilesSetup,
+    host: &Host,
+) -> std::io::Result<Renderer> {
+    let renderer = Renderer::new(host, &fixture.vars, setup.allow_commands)
+        .with_secrets(&setup.secrets)
+        .strict(setup.strict);
+
+    match fixture.repo_dir() {
+        Some(repo_dir) => renderer.with_partials(repo_dir).map_err(|e| {
+            error!("failed to load template partials for {}", fixture.name);
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+        }),
+        None => Ok(renderer),
+    }
+}
+
+/// Resolve a file's destination for the host, rendering any template expressions and
+/// environment variables in it
+pub(crate) fn resolve_dest(
+    file: &File,
+    host: &Host,
+    renderer: &Renderer,
+) -> std::io::Result<Option<PathBuf>> {
+    let Some(dest) = file.dest.clone().select_for(host) else {
+        return Ok(None);
+    };
+
+    renderer.render_path(&dest).map(Some).map_err(|e| {
+        error!(
+            "failed to resolve destination: {}",
+            template::error_chain(&e)
+        );
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    })
+}
+
+/// Destinations of a fixture's files and directories
+pub(crate) fn managed_paths(setup: &FilesSetup, host: &Host, renderer: &Renderer) -> Vec<PathBuf> {
+    setup
+        .files
+        .iter()
+        .filter(|file| file.state != State::Absent)
+        .filter_map(|file| resolve_dest(file, host, renderer).ok().flatten())
+        .collect()
+}
+
+/// Destinations of every fixture that applies to the host, so purging a directory doesn't remove
+/// what another fixture put there, like fonts extracted by an archive fixture
+pub(crate) fn all_managed_paths(fixtures: &[Fixture], host: &Host) -> Vec<PathBuf> {
+    let archives = ArchiveState::load(&ArchiveState::state_file()).ok();
+
+    let mut managed = Vec::new();
+    for fixture in fixtures.iter().filter(|fixture| !fixture.skip_for(host)) {
+        let plain = Renderer::new(host, &fixture.vars, false);
+        match &fixture.fixture_type {
+            FixtureType::Files(setup) => {
+                if let Ok(renderer) = renderer(fixture, setup, host) {
+                    managed.extend(managed_paths(setup, host, &renderer));
+                }
+            }
+            FixtureType::Repository(setup) => managed.extend(plain.render_path(&setup.path).ok()),
+            FixtureType::Systemd(setup) => {
+                let unit_dir = systemd::unit_dir(setup.scope);
+                managed.extend(setup.units.iter().map(|unit| unit_dir.join(unit.name())));
+            }
+            FixtureType::Archive(setup) => {
+                if let Ok(dest) = plain.render_path(&setup.dest) {
+                    if let Some(archives) = &archives {
+                        managed.extend(archives.files(&dest));
+                    }
+                    managed.push(dest);
+                }
+            }
+            FixtureType::Gitconfig(setup) => managed.extend(plain.render_path(&setup.path).ok()),
+            FixtureType::Packages(_) | FixtureType::Command(_) => {}
+        }
+    }
+
+    managed
+}
+
+/// Everything in `dir` that isn't managed. Subdirectories containing managed paths are kept,
+/// and only their unmanaged entries are listed. Sockets, FIFOs and devices, like gpg-agent's
+/// socket in `~/.gnupg`, can't be backed up, so they and the directories containing them are
+/// kept too.
+pub(crate) fn unmanaged(dir: &Path, managed: &[PathBuf]) -> std::io::Result<Vec<PathBuf>> {
+    let mut entries = std::fs::read_dir(dir)
+        .inspect_err(|_| error!("failed to read directory: {}", &dir.to_string_lossy()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.path());
+
+    let mut found = Vec::new();
+    for entry in entries {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if managed.contains(&path) || is_special(&file_type) {
+            continue;
+        }
+        if file_type.is_dir()
+            && (managed.iter().any(|managed| managed.starts_with(&path))
+                || contains_special(&path)?)
+        {
+            found.extend(unmanaged(&path, managed)?);
+            continue;
+        }
+        found.push(path);
+    }
+
+    Ok(found)
+}
+
+fn is_special(file_type: &std::fs::FileType) -> bool {
+    file_type.is_socket()
+        || file_type.is_fifo()
+        || file_type.is_block_device()
+        || file_type.is_char_device()
+}
+
+fn contains_special(dir: &Path) -> std::io::Result<bool> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if is_special(&file_type) || (file_type.is_dir() && contains_special(&entry.path())?) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Names of the secrets used by a fixture's templates and destination paths
+pub(crate) fn used_secrets(
+    setup: &FilesSetup,
+    host: &Host,
+    renderer: &Renderer,
+) -> BTreeSet<String> {
+    let mut used = BTreeSet::new();
+    for file in &setup.files {
+        if let Some(dest) = file.dest.clone().select_for(host) {
+            let dest = dest.to_string_lossy();
+            used.extend(renderer.used_secrets(&dest, &dest).unwrap_or_default());
+        }
+
+        let Some(src) = file.src.clone().resolve_for(host) else {
+            continue;
+        };
+        if file.raw {
+            continue;
+        }
+        if let Ok(input) = std::fs::read_to_string(&src) {
+            used.extend(
+                renderer
+                    .used_secrets(&src.to_string_lossy(), &input)
+                    .unwrap_or_default(),
+            );
+        }
+    }
+
+    used
+}
+
+/// Render fixtures to stdout, or into `out` as a tree mirroring the destination paths
+pub fn render_fixtures(
+    fixtures: Vec<Fixture>,
+    host: &Host,
+    file: Option<PathBuf>,
+    out: Option<PathBuf>,
+) -> std::io::Result<()> {
+    let file = file.map(|file| file.resolve().to_path_buf());
+    let mut stdout = std::io::stdout().lock();
+    let mut failures = Vec::new();
+
+    for fixture in fixtures {
+        if fixture.skip_for(host) {
+            continue;
+        }
+
+        for rendered in render_fixture(&fixture, host) {
+            let rendered = match rendered {
+                Ok(rendered) => rendered,
+                Err(e) => {
+                    failures.push((fixture.name.clone(), e));
+                    continue;
+                }
+            };
+
+            if file.as_ref().is_some_and(|file| file != &rendered.dest) {
+                continue;
+            }
+
+            match &out {
+                Some(out) => {
+                    let path = staging_path(out, &rendered.dest);
+                    std::fs::create_dir_all(path.parent().unwrap()).inspect_err(|_| {
+                        error!(
+                            "failed to create parent directory(s): {}",
+                            &path.to_string_lossy()
+                        )
+                    })?;
+                    std::fs::write(&path, &rendered.content).inspect_err(|_| {
+                        error!("failed to write rendered file: {}", &path.to_string_lossy())
+                    })?;
+                }
+                None if file.is_some() => write!(stdout, "{}", secrets::redact(&rendered.content))?,
+                None => {
+                    writeln!(stdout, "==> {} <==", rendered.dest.to_string_lossy())?;
+                    writeln!(stdout, "{}", secrets::redact(&rendered.content))?;
+                }
+            }
+        }
+    }
+
+    report_failures(&failures)
+}
+
+/// List the variables each template references and whether the context provides them
+pub fn list_variables(fixtures: Vec<Fixture>, host: &Host) -> std::io::Result<()> {
+    let mut failures = Vec::new();
+
+    for fixture in fixtures {
+        let FixtureType::Files(setup) = &fixture.fixture_type else {
+            continue;
+        };
+        let renderer = match renderer(&fixture, setup, host) {
+            Ok(renderer) => renderer,
+            Err(e) => {
+                failures.push((fixture.name.clone(), e));
+                continue;
+            }
+        };
+        let provided = renderer.provided();
+
+        for file in setup.files.iter().filter(|file| !file.raw) {
+            let Some(src) = file.src.clone().resolve_for(host) else {
+                continue;
+            };
+            let referenced = std::fs::read_to_string(&src).and_then(|input| {
+                renderer
+                    .referenced(&src, &input)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            });
+            let referenced = match referenced {
+                Ok(referenced) => referenced,
+                Err(e) => {
+                    failures.push((fixture.name.clone(), e));
+                    continue;
+                }
+            };
+
+            println!("{}:", src.to_string_lossy());
+            for variable in referenced {
+                if provided.contains(&variable) {
+                    println!("  {}", variable);
+                } else {
+                    println!("  {} (missing)", variable);
+                }
+            }
+        }
+    }
+
+    report_failures(&failures)
+}
+
+/// Location of a destination file inside a staging tree.
+///
+/// Files under the home directory are placed under `~/` so the tree is the same
+/// regardless of which user renders it.
+pub fn staging_path(out: &Path, dest: &Path) -> PathBuf {
+    if let Some(home) = dirs::home_dir() {
+        if let Ok(relative) = dest.strip_prefix(home) {
+            return out.join("~").join(relative);
+        }
+    }
+
+    out.join(dest.strip_prefix("/").unwrap_or(dest))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::fixture::{File, FileDefinition, FilesSetup};
+
+    fn fixture(src: PathBuf, dest: FileDefinition) -> Fixture {
+        Fixture {
+            name: "test-fixture".into(),
+            include_for: None,
+            exclude_for: None,
+            vars: Default::default(),
+            dir: Default::default(),
+            fixture_type: FixtureType::Files(FilesSetup {
+                files: vec![File::new(FileDefinition::Single(src), dest)],
+                root: false,
+                allow_commands: false,
+                strict: false,
+                secrets: HashMap::from_iter(vec![("name".into(), "world".into())]),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_render_fixture_with_overrides() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+
+        let src = test_dir.path().join("source.conf");
+        std::fs::write(&src, "Hello, {{ name }} from {{ hostname }}!").unwrap();
+
+        let dest = FileDefinition::Multiple(HashMap::from_iter(vec![
+            ("desktop".into(), PathBuf::from("/etc/desktop.conf")),
+            ("default".into(), PathBuf::from("/etc/default.conf")),
+        ]));
+        let host = Host::current().with_overrides(Some("desktop".into()), None, None);
+
+        let rendered: Vec<_> = render_fixture(&fixture(src.clone(), dest), &host)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(
+            rendered,
+            vec![RenderedFile {
+                src,
+                dest: PathBuf::from("/etc/desktop.conf"),
+                content: "Hello, world from desktop!".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_render_fixtures_to_directory() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let out = test_dir.path().join("out");
+
+        let src = test_dir.path().join("source.conf");
+        std::fs::write(&src, "Hello, {{ name }}!").unwrap();
+
+        let dest = FileDefinition::Single(PathBuf::from("/etc/dest.conf"));
+
+        render_fixtures(
+            vec![fixture(src, dest)],
+            &Host::current(),
+            None,
+            Some(out.clone()),
+        )
+        .unwrap();
+
+        let content = std::fs::read_to_string(out.join("etc/dest.conf")).unwrap();
+        assert_eq!(content, "Hello, world!");
+    }
+
+    #[test]
+    fn test_render_fixtures_continues_after_failure() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let out = test_dir.path().join("out");
+
+        let broken = test_dir.path().join("broken.conf");
+        std::fs::write(&broken, "Hello, {{ missing }}!").unwrap();
+        let src = test_dir.path().join("source.conf");
+        std::fs::write(&src, "Hello, {{ name }}!").unwrap();
+
+        let fixtures = vec![
+            fixture(broken, FileDefinition::Single("/etc/broken.conf".into())),
+            fixture(src, FileDefinition::Single("/etc/dest.conf".into())),
+        ];
+
+        let result = render_fixtures(fixtures, &Host::current(), None, Some(out.clone()));
+
+        assert!(result.is_err());
+        assert!(!out.join("etc/broken.conf").exists());
+        assert!(out.join("etc/dest.conf").exists());
+    }
+
+    #[test]
+    fn test_unmanaged() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let ssh = test_dir.path().join(".ssh");
+        std::fs::create_dir_all(ssh.join("config.d")).unwrap();
+        std::fs::create_dir_all(ssh.join("old")).unwrap();
+        for file in [
+            "config",
+            "known_hosts",
+            "config.d/work",
+            "config.d/stale",
+            "old/id_rsa",
+        ] {
+            std::fs::write(ssh.join(file), "").unwrap();
+        }
+
+        let managed = vec![ssh.clone(), ssh.join("config"), ssh.join("config.d/work")];
+        assert_eq!(
+            unmanaged(&ssh, &managed).unwrap(),
+            vec![
+                ssh.join("config.d/stale"),
+                ssh.join("known_hosts"),
+                ssh.join("old"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_all_managed_paths() {
+        let fixtures: Vec<Fixture> = serde_json::from_str(
+            r#"[
+                {"type": "files", "files": [{"src": "fonts.conf", "dest": "/fonts/fonts.conf"}]},
+                {"type": "gitconfig", "path": "/home/.gitconfig", "keys": {"user.name": "me"}},
+                {"type": "command", "command": "true"}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            all_managed_paths(&fixtures, &Host::current()),
+            vec![
+                PathBuf::from("/fonts/fonts.conf"),
+                PathBuf::from("/home/.gitconfig")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unmanaged_keeps_sockets() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let gnupg = test_dir.path().join(".gnupg");
+        std::fs::create_dir_all(gnupg.join("agent")).unwrap();
+        std::fs::write(gnupg.join("agent/stale"), "").unwrap();
+        std::fs::write(gnupg.join("random_seed"), "").unwrap();
+        let _socket =
+            std::os::unix::net::UnixListener::bind(gnupg.join("agent/S.gpg-agent")).unwrap();
+
+        assert_eq!(
+            unmanaged(&gnupg, &[]).unwrap(),
+            vec![gnupg.join("agent/stale"), gnupg.join("random_seed")]
+        );
+    }
+
+    #[test]
+    fn test_staging_path_home() {
+        let home = dirs::home_dir().unwrap();
+        let out = PathBuf::from("/tmp/out");
+
+        assert_eq!(
+            staging_path(&out, &home.join(".zshrc")),
+            PathBuf::from("/tmp/out/~/.zshrc")
+        );
+        assert_eq!(
+            staging_path(&out, Path::new("/etc/hosts")),
+            PathBuf::from("/tmp/out/etc/hosts")
+        );
+    }
+}
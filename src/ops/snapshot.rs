@@ -0,0 +1,270 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use log::error;
+use similar::TextDiff;
+
+use crate::{
+    fixture::FixtureType,
+    host::Host,
+    ops::{load::load_fixtures_for, render},
+    secrets,
+};
+
+/// Simulated machines to render snapshots for, keyed by name
+pub type Machines = BTreeMap<String, Host>;
+
+pub fn load_machines(repo_dir: &Path) -> std::io::Result<Machines> {
+    let machines_file = repo_dir.join("machines.json");
+    let machines = std::fs::read_to_string(&machines_file).inspect_err(|_| {
+        error!(
+            "failed to read machines file: {}",
+            &machines_file.to_string_lossy()
+        )
+    })?;
+
+    serde_json::from_str(&machines)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Render all applicable fixtures for each machine and compare the results to the
+/// snapshots in `<repo>/snapshots/<machine>`, or rewrite them if `update` is set.
+///
+/// Returns whether every machine matched its snapshot.
+pub fn test_snapshots(repo_dir: &Path, update: bool) -> std::io::Result<bool> {
+    let machines = load_machines(repo_dir)?;
+    let snapshot_dir = repo_dir.join("snapshots");
+
+    let mut passed = true;
+    for (name, host) in machines {
+        let actual = match render_machine(repo_dir, &host) {
+            Ok(actual) => actual,
+            Err(e) => {
+                println!("{} ... FAILED ({})", name, e);
+                passed = false;
+                continue;
+            }
+        };
+        let machine_dir = snapshot_dir.join(&name);
+
+        if update {
+            write_snapshot(&machine_dir, &actual)?;
+            println!("Updated snapshot for {}", name);
+            continue;
+        }
+
+        let expected = read_snapshot(&machine_dir)?;
+        if compare_snapshot(&name, &expected, &actual) {
+            println!("{} ... ok", name);
+        } else {
+            println!("{} ... FAILED", name);
+            passed = false;
+        }
+    }
+
+    Ok(passed)
+}
+
+fn render_machine(repo_dir: &Path, host: &Host) -> std::io::Result<BTreeMap<PathBuf, String>> {
+    let fixtures = load_fixtures_for(repo_dir.to_path_buf(), vec![], host)?;
+
+    let mut files = BTreeMap::new();
+    let mut failures = Vec::new();
+    for mut fixture in fixtures {
+        if fixture.skip_for(host) {
+            continue;
+        }
+
+        // snapshots are committed, so they never contain secret values
+        if let FixtureType::Files(setup) = &mut fixture.fixture_type {
+            setup.secrets = secrets::placeholders(&setup.secrets);
+        }
+
+        for rendered in render::render_fixture(&fixture, host) {
+            let rendered = match rendered {
+                Ok(rendered) => rendered,
+                Err(e) => {
+                    failures.push((fixture.name.clone(), e));
+                    continue;
+                }
+            };
+            files.insert(
+                render::staging_path(Path::new(""), &rendered.dest),
+                rendered.content,
+            );
+        }
+    }
+
+    render::report_failures(&failures)?;
+    Ok(files)
+}
+
+fn write_snapshot(dir: &Path, files: &BTreeMap<PathBuf, String>) -> std::io::Result<()> {
+    if dir.exists() {
+        std::fs::remove_dir_all(dir).inspect_err(|_| {
+            error!(
+                "failed to remove snapshot directory: {}",
+                &dir.to_string_lossy()
+            )
+        })?;
+    }
+
+    for (path, content) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, content)
+            .inspect_err(|_| error!("failed to write snapshot: {}", &path.to_string_lossy()))?;
+    }
+
+    Ok(())
+}
+
+fn read_snapshot(dir: &Path) -> std::io::Result<BTreeMap<PathBuf, String>> {
+    let mut files = BTreeMap::new();
+    if dir.exists() {
+        read_snapshot_dir(dir, dir, &mut files)?;
+    }
+
+    Ok(files)
+}
+
+fn read_snapshot_dir(
+    root: &Path,
+    dir: &Path,
+    files: &mut BTreeMap<PathBuf, String>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            read_snapshot_dir(root, &path, files)?;
+        } else {
+            let content = std::fs::read_to_string(&path)
+                .inspect_err(|_| error!("failed to read snapshot: {}", &path.to_string_lossy()))?;
+            files.insert(path.strip_prefix(root).unwrap().to_path_buf(), content);
+        }
+    }
+
+    Ok(())
+}
+
+/// Print a unified diff for every file that differs, returning whether they all matched
+fn compare_snapshot(
+    machine: &str,
+    expected: &BTreeMap<PathBuf, String>,
+    actual: &BTreeMap<PathBuf, String>,
+) -> bool {
+    let paths: BTreeSet<_> = expected.keys().chain(actual.keys()).collect();
+
+    let mut matches = true;
+    for path in paths {
+        let old = expected.get(path).map(String::as_str).unwrap_or_default();
+        let new = actual.get(path).map(String::as_str).unwrap_or_default();
+
+        if expected.contains_key(path) == actual.contains_key(path) && old == new {
+            continue;
+        }
+        matches = false;
+
+        let path = Path::new(machine).join(path);
+        let diff = TextDiff::from_lines(old, new);
+        let mut unified = diff.unified_diff();
+        unified.header(
+            &format!("snapshot/{}", path.to_string_lossy()),
+            &format!("rendered/{}", path.to_string_lossy()),
+        );
+
+        print!("{}", secrets::redact(&unified.to_string()));
+    }
+
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup_repo(repo_dir: &Path) {
+        let fixture_dir = repo_dir.join("hosts");
+        std::fs::create_dir(&fixture_dir).unwrap();
+        std::fs::write(
+            fixture_dir.join("fixture.json"),
+            r#"{"type": "files", "files": [{"src": "hosts", "dest": "/etc/hosts"}]}"#,
+        )
+        .unwrap();
+        std::fs::write(fixture_dir.join("hosts"), "127.0.1.1 {{ hostname }}\n").unwrap();
+
+        std::fs::write(
+            repo_dir.join("machines.json"),
+            r#"{
+                "desktop": {"hostname": "desktop", "os": "linux", "arch": "x86_64"},
+                "laptop": {"hostname": "laptop", "os": "linux", "arch": "aarch64", "tags": ["work"]}
+            }"#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_update_snapshots() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        setup_repo(test_dir.path());
+
+        assert!(test_snapshots(test_dir.path(), true).unwrap());
+
+        let snapshot = test_dir.path().join("snapshots/laptop/etc/hosts");
+        assert_eq!(
+            std::fs::read_to_string(snapshot).unwrap(),
+            "127.0.1.1 laptop\n"
+        );
+        assert!(test_snapshots(test_dir.path(), false).unwrap());
+    }
+
+    #[test]
+    fn test_snapshot_secrets() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        setup_repo(test_dir.path());
+        let fixture_dir = test_dir.path().join("hosts");
+        std::fs::write(
+            fixture_dir.join("secrets.json"),
+            r#"{"NAS_TOKEN": "snapshot-secret-value", "PASS": {"command": "exit 1"}}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            fixture_dir.join("hosts"),
+            "127.0.1.1 {{ hostname }}\n# {{ NAS_TOKEN }} {{ PASS }}\n",
+        )
+        .unwrap();
+
+        assert!(test_snapshots(test_dir.path(), true).unwrap());
+
+        let snapshot = test_dir.path().join("snapshots/laptop/etc/hosts");
+        assert_eq!(
+            std::fs::read_to_string(snapshot).unwrap(),
+            "127.0.1.1 laptop\n# <secret:NAS_TOKEN> <secret:PASS>\n"
+        );
+    }
+
+    #[test]
+    fn test_snapshot_mismatch() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        setup_repo(test_dir.path());
+
+        test_snapshots(test_dir.path(), true).unwrap();
+        std::fs::write(
+            test_dir.path().join("snapshots/desktop/etc/hosts"),
+            "127.0.1.1 old-desktop\n",
+        )
+        .unwrap();
+
+        assert!(!test_snapshots(test_dir.path(), false).unwrap());
+    }
+
+    #[test]
+    fn test_missing_snapshot() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        setup_repo(test_dir.path());
+
+        assert!(!test_snapshots(test_dir.path(), false).unwrap());
+    }
+}
@@ -0,0 +1,256 @@
+use std::process::{Command, Stdio};
+
+use log::error;
+
+use crate::{fixture::Manager, privilege::PrivilegedBatch};
+
+/// Queries and installs packages. Implemented for the supported package managers, and by fakes
+/// in tests.
+pub trait PackageManager {
+    fn name(&self) -> String;
+
+    /// The packages that aren't installed yet
+    fn missing(&self, packages: &[String]) -> std::io::Result<Vec<String>>;
+
+    /// Command installing the packages without asking for confirmation
+    fn install_command(&self, packages: &[String]) -> Vec<String>;
+
+    /// Whether the install command has to run as root
+    fn needs_root(&self) -> bool;
+}
+
+/// The package manager installed on this machine
+pub struct System(pub Manager);
+
+impl PackageManager for System {
+    fn name(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn missing(&self, packages: &[String]) -> std::io::Result<Vec<String>> {
+        if self.0 == Manager::Cargo {
+            let output = output(&["cargo", "install", "--list"])?;
+            let installed = cargo_installed(&String::from_utf8_lossy(&output.stdout));
+            return Ok(packages
+                .iter()
+                .filter(|package| !installed.contains(package))
+                .cloned()
+                .collect());
+        }
+
+        let mut missing = Vec::new();
+        for package in packages {
+            if !self.is_installed(package)? {
+                missing.push(package.clone());
+            }
+        }
+        Ok(missing)
+    }
+
+    fn install_command(&self, packages: &[String]) -> Vec<String> {
+        let command: &[&str] = match self.0 {
+            Manager::Pacman => &["pacman", "-S", "--needed", "--noconfirm"],
+            Manager::Apt => &["apt-get", "install", "-y"],
+            Manager::Dnf => &["dnf", "install", "-y"],
+            Manager::Emerge => &["emerge", "--noreplace"],
+            Manager::Cargo => &["cargo", "install"],
+            Manager::Flatpak => &["flatpak", "install", "-y", "--noninteractive"],
+        };
+
+        let mut command: Vec<_> = command.iter().map(|arg| arg.to_string()).collect();
+        command.extend_from_slice(packages);
+        command
+    }
+
+    fn needs_root(&self) -> bool {
+        self.0 != Manager::Cargo
+    }
+}
+
+impl System {
+    fn is_installed(&self, package: &str) -> std::io::Result<bool> {
+        let output = match self.0 {
+            Manager::Pacman => output(&["pacman", "-Q", package])?,
+            Manager::Apt => {
+                // dpkg also knows about removed packages that left their config files behind
+                let output = output(&["dpkg-query", "-W", "-f=${db:Status-Status}", package])?;
+                return Ok(output.stdout == b"installed");
+            }
+            Manager::Dnf => output(&["rpm", "-q", package])?,
+            Manager::Emerge => output(&["portageq", "has_version", "/", package])?,
+            Manager::Flatpak => output(&["flatpak", "info", package])?,
+            Manager::Cargo => unreachable!("cargo packages are listed all at once"),
+        };
+
+        Ok(output.status.success())
+    }
+}
+
+fn output(command: &[&str]) -> std::io::Result<std::process::Output> {
+    Command::new(command[0])
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .output()
+        .inspect_err(|_| error!("failed to run {}", command[0]))
+}
+
+/// Names of the crates in the output of `cargo install --list`, where each crate is on an
+/// unindented line like `ripgrep v14.1.0:` followed by its binaries
+fn cargo_installed(list: &str) -> Vec<String> {
+    list.lines()
+        .filter(|line| !line.starts_with(char::is_whitespace))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// Install the packages that are missing, as root through the batch if the manager needs it.
+/// The batch runs right away, so fixtures after this one can use the packages. Returns the
+/// packages that were missing.
+pub fn install(
+    manager: &dyn PackageManager,
+    packages: &[String],
+    batch: &mut PrivilegedBatch,
+) -> std::io::Result<Vec<String>> {
+    let missing = manager.missing(packages)?;
+    if missing.is_empty() {
+        println!("{} packages are up to date", manager.name());
+        return Ok(missing);
+    }
+
+    println!("Installing {} with {}", missing.join(", "), manager.name());
+    let command = manager.install_command(&missing);
+    if manager.needs_root() {
+        batch.command(&command);
+        batch.flush()?;
+        return Ok(missing);
+    }
+
+    let status = Command::new(&command[0])
+        .args(&command[1..])
+        .status()
+        .inspect_err(|_| error!("failed to run {}", &command[0]))?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "`{}` exited with {}",
+            command.join(" "),
+            status
+        )));
+    }
+
+    Ok(missing)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::privilege::Escalation;
+
+    /// Has some packages installed, and "installs" by touching a marker file
+    struct Fake {
+        installed: Vec<String>,
+        marker: PathBuf,
+        root: bool,
+    }
+
+    impl PackageManager for Fake {
+        fn name(&self) -> String {
+            "fake".to_string()
+        }
+
+        fn missing(&self, packages: &[String]) -> std::io::Result<Vec<String>> {
+            Ok(packages
+                .iter()
+                .filter(|package| !self.installed.contains(package))
+                .cloned()
+                .collect())
+        }
+
+        fn install_command(&self, _packages: &[String]) -> Vec<String> {
+            vec!["touch".into(), self.marker.to_string_lossy().to_string()]
+        }
+
+        fn needs_root(&self) -> bool {
+            self.root
+        }
+    }
+
+    #[test]
+    fn test_install_missing() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let manager = Fake {
+            installed: vec!["neovim".into()],
+            marker: test_dir.path().join("installed"),
+            root: false,
+        };
+        let mut batch = PrivilegedBatch::new(Escalation::None);
+
+        let missing = install(&manager, &["neovim".into(), "ripgrep".into()], &mut batch).unwrap();
+        assert_eq!(missing, vec!["ripgrep".to_string()]);
+        assert!(manager.marker.exists());
+    }
+
+    #[test]
+    fn test_install_up_to_date() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let manager = Fake {
+            installed: vec!["neovim".into()],
+            marker: test_dir.path().join("installed"),
+            root: true,
+        };
+        let mut batch = PrivilegedBatch::new(Escalation::None);
+
+        assert!(install(&manager, &["neovim".into()], &mut batch)
+            .unwrap()
+            .is_empty());
+        batch.run().unwrap();
+        assert!(!manager.marker.exists());
+    }
+
+    #[test]
+    fn test_install_as_root() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let manager = Fake {
+            installed: vec![],
+            marker: test_dir.path().join("installed"),
+            root: true,
+        };
+        let mut batch = PrivilegedBatch::new(Escalation::None);
+
+        let earlier = test_dir.path().join("earlier");
+        let later = test_dir.path().join("later");
+        batch.command(&["touch".into(), earlier.to_string_lossy().to_string()]);
+
+        install(&manager, &["neovim".into()], &mut batch).unwrap();
+        // installed along with what was queued before, ahead of the fixtures after it
+        assert!(earlier.exists());
+        assert!(manager.marker.exists());
+
+        batch.command(&["touch".into(), later.to_string_lossy().to_string()]);
+        assert!(!later.exists());
+        batch.run().unwrap();
+        assert!(later.exists());
+    }
+
+    #[test]
+    fn test_cargo_installed() {
+        let list = "\
+bat v0.24.0:
+    bat
+ripgrep v14.1.0:
+    rg
+";
+        assert_eq!(cargo_installed(list), vec!["bat", "ripgrep"]);
+    }
+
+    #[test]
+    fn test_install_command() {
+        assert_eq!(
+            System(Manager::Pacman).install_command(&["neovim".into()]),
+            vec!["pacman", "-S", "--needed", "--noconfirm", "neovim"]
+        );
+        assert!(!System(Manager::Cargo).needs_root());
+    }
+}
@@ -0,0 +1,626 @@
+use std::{
+    ffi::CString,
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use log::error;
+
+use crate::secrets::PrivateTempDir;
+
+/// The command used to gain root for fixtures with `"root": true`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Escalation {
+    #[default]
+    Sudo,
+    Doas,
+    Pkexec,
+    Run0,
+    /// Write directly, for machines where spaceconf already runs as root
+    None,
+}
+
+impl Escalation {
+    fn command(&self) -> Option<&'static str> {
+        match self {
+            Escalation::Sudo => Some("sudo"),
+            Escalation::Doas => Some("doas"),
+            Escalation::Pkexec => Some("pkexec"),
+            Escalation::Run0 => Some("run0"),
+            Escalation::None => None,
+        }
+    }
+}
+
+pub fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Permissions and ownership a destination should end up with
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Attributes {
+    pub mode: u32,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+}
+
+impl Attributes {
+    pub fn new(mode: u32) -> Self {
+        Self {
+            mode: mode & 0o7777,
+            ..Default::default()
+        }
+    }
+
+    /// Argument for `chown`, if ownership should change
+    fn chown_spec(&self) -> Option<String> {
+        match (&self.owner, &self.group) {
+            (None, None) => None,
+            (Some(owner), None) => Some(owner.clone()),
+            (owner, Some(group)) => Some(format!("{}:{}", owner.as_deref().unwrap_or(""), group)),
+        }
+    }
+}
+
+/// Look up a user by name, or parse it as a uid
+pub fn user_id(user: &str) -> std::io::Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+
+    let name = CString::new(user).map_err(std::io::Error::other)?;
+    // SAFETY: name is a valid C string, and the entry is read before any other lookup
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("user {} does not exist", user),
+        ));
+    }
+
+    // SAFETY: checked for null above
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+/// Look up a group by name, or parse it as a gid
+pub fn group_id(group: &str) -> std::io::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group).map_err(std::io::Error::other)?;
+    // SAFETY: name is a valid C string, and the entry is read before any other lookup
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("group {} does not exist", group),
+        ));
+    }
+
+    // SAFETY: checked for null above
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+struct PendingWrite {
+    dest: PathBuf,
+    content: String,
+    attributes: Attributes,
+}
+
+enum Operation {
+    Write(PendingWrite),
+    Remove(PathBuf),
+    RemoveDir(PathBuf),
+    CreateDir(PathBuf, Attributes),
+    Command(Vec<String>),
+}
+
+/// Writes, removals and commands that need root, collected during a run so the escalation
+/// command only runs (and prompts) once. They are carried out in the order they were added.
+pub struct PrivilegedBatch {
+    escalation: Escalation,
+    operations: Vec<Operation>,
+}
+
+impl PrivilegedBatch {
+    pub fn new(escalation: Escalation) -> Self {
+        Self {
+            escalation,
+            operations: Vec::new(),
+        }
+    }
+
+    pub fn write(&mut self, dest: &Path, content: &str, attributes: &Attributes) {
+        self.operations.push(Operation::Write(PendingWrite {
+            dest: dest.to_path_buf(),
+            content: content.to_string(),
+            attributes: attributes.clone(),
+        }));
+    }
+
+    pub fn remove(&mut self, dest: &Path) {
+        self.operations.push(Operation::Remove(dest.to_path_buf()));
+    }
+
+    /// Remove a directory and everything in it
+    pub fn remove_dir(&mut self, dest: &Path) {
+        self.operations
+            .push(Operation::RemoveDir(dest.to_path_buf()));
+    }
+
+    /// Create a directory if it doesn't exist, and give it the mode and ownership
+    pub fn create_dir(&mut self, dest: &Path, attributes: &Attributes) {
+        self.operations
+            .push(Operation::CreateDir(dest.to_path_buf(), attributes.clone()));
+    }
+
+    /// Run a command as root, like `systemctl daemon-reload` after installing a unit
+    pub fn command(&mut self, args: &[String]) {
+        self.operations.push(Operation::Command(args.to_vec()));
+    }
+
+    /// Perform the operations added so far and keep collecting, for operations that later
+    /// fixtures depend on, like installing packages
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        let pending = Self {
+            escalation: self.escalation,
+            operations: std::mem::take(&mut self.operations),
+        };
+        pending.run()
+    }
+
+    fn writes(&self) -> impl Iterator<Item = &PendingWrite> {
+        self.operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Write(write) => Some(write),
+                _ => None,
+            })
+    }
+
+    /// Perform every operation, directly if spaceconf is already root, otherwise in a single
+    /// escalated shell. Fails if any step fails.
+    pub fn run(self) -> std::io::Result<()> {
+        if self.operations.is_empty() {
+            return Ok(());
+        }
+
+        match self.escalation.command() {
+            Some(command) if !is_root() => self.run_escalated(command),
+            _ => self.run_direct(),
+        }
+    }
+
+    fn run_direct(self) -> std::io::Result<()> {
+        for operation in &self.operations {
+            match operation {
+                Operation::Write(write) => {
+                    std::fs::create_dir_all(write.dest.parent().unwrap()).inspect_err(|_| {
+                        error!(
+                            "failed to create parent directory(s): {}",
+                            &write.dest.to_string_lossy()
+                        )
+                    })?;
+
+                    let staged = staging_path(&write.dest);
+                    let result = write_staged(&staged, write);
+                    if result.is_err() {
+                        let _ = std::fs::remove_file(&staged);
+                    }
+                    result?;
+                }
+                Operation::Remove(dest) => match std::fs::remove_file(dest) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        error!("failed to remove: {}", &dest.to_string_lossy());
+                        return Err(e);
+                    }
+                    _ => {}
+                },
+                Operation::RemoveDir(dest) => match std::fs::remove_dir_all(dest) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        error!("failed to remove: {}", &dest.to_string_lossy());
+                        return Err(e);
+                    }
+                    _ => {}
+                },
+                Operation::CreateDir(dest, attributes) => create_dir(dest, attributes)?,
+                Operation::Command(args) => {
+                    let Some((program, rest)) = args.split_first() else {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "empty command",
+                        ));
+                    };
+                    let status = std::process::Command::new(program)
+                        .args(rest)
+                        .status()
+                        .inspect_err(|_| error!("failed to run {}", program))?;
+                    if !status.success() {
+                        return Err(std::io::Error::other(format!(
+                            "`{}` exited with {}",
+                            args.join(" "),
+                            status
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn run_escalated(self, command: &str) -> std::io::Result<()> {
+        // removed when dropped, whether or not the writes succeed
+        let temp_dir = PrivateTempDir::new()?;
+        let mut temp_files = Vec::new();
+        for (index, write) in self.writes().enumerate() {
+            let temp_file = temp_dir.path().join(index.to_string());
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&temp_file)
+                .and_then(|mut file| file.write_all(write.content.as_bytes()))
+                .inspect_err(|_| {
+                    error!(
+                        "failed to write temporary file: {}",
+                        &temp_file.to_string_lossy()
+                    )
+                })?;
+            temp_files.push(temp_file);
+        }
+
+        let status = std::process::Command::new(command)
+            .arg("sh")
+            .arg("-c")
+            .arg(self.script(&temp_files))
+            .status()
+            .inspect_err(|_| error!("failed to run {}", command))?;
+
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "{} exited with {}, root files may not have been written",
+                command, status
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Shell script performing each operation, stopping at the first failure. Files are
+    /// copied to a new temporary file next to their destination, created by `mktemp` so a
+    /// planted file or symlink is never written through, given their final owner and mode, then
+    /// renamed over the destination. A destination that is a symlink is replaced by the file
+    /// rather than followed.
+    fn script(&self, sources: &[PathBuf]) -> String {
+        let mut script = String::from("set -e\n");
+        if !sources.is_empty() {
+            script += "staged=\n\
+                       cleanup() { if [ -n \"$staged\" ]; then rm -f -- \"$staged\"; fi; }\n\
+                       trap cleanup EXIT\n";
+        }
+
+        let mut sources = sources.iter();
+        for operation in &self.operations {
+            match operation {
+                Operation::Write(write) => {
+                    let Some(src) = sources.next() else {
+                        break;
+                    };
+                    let template = format!("{}.XXXXXX", staging_path(&write.dest).display());
+                    script += &format!(
+                        "mkdir -p -- {}\nstaged=$(mktemp {})\ncp -- {} \"$staged\"\n",
+                        quote(write.dest.parent().unwrap()),
+                        quote(template),
+                        quote(src),
+                    );
+                    // chown clears setuid and setgid bits, so the mode comes after it
+                    if let Some(spec) = write.attributes.chown_spec() {
+                        script += &format!("chown -- {} \"$staged\"\n", quote(spec));
+                    }
+                    script += &format!(
+                        "chmod {:o} -- \"$staged\"\nmv -f -- \"$staged\" {}\n",
+                        write.attributes.mode,
+                        quote(&write.dest)
+                    );
+                }
+                Operation::Remove(dest) => script += &format!("rm -f -- {}\n", quote(dest)),
+                Operation::RemoveDir(dest) => script += &format!("rm -rf -- {}\n", quote(dest)),
+                Operation::CreateDir(dest, attributes) => {
+                    script += &format!("mkdir -p -- {}\n", quote(dest));
+                    if let Some(spec) = attributes.chown_spec() {
+                        script += &format!("chown -- {} {}\n", quote(spec), quote(dest));
+                    }
+                    script += &format!("chmod {:o} -- {}\n", attributes.mode, quote(dest));
+                }
+                Operation::Command(args) => {
+                    let args: Vec<_> = args.iter().map(quote).collect();
+                    script += &format!("{}\n", args.join(" "));
+                }
+            }
+        }
+
+        script
+    }
+}
+
+/// Temporary name next to the destination, so it can be renamed into place
+fn staging_path(dest: &Path) -> PathBuf {
+    let name = dest.file_name().unwrap().to_string_lossy();
+    dest.with_file_name(format!(".{}.spaceconf-new", name))
+}
+
+/// Write the content to `staged` with only the owner able to read it, apply the final mode and
+/// ownership, then rename it over the destination
+fn write_staged(staged: &Path, write: &PendingWrite) -> std::io::Result<()> {
+    let _ = std::fs::remove_file(staged);
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(staged)
+        .inspect_err(|_| {
+            error!(
+                "failed to open destination file: {}",
+                &write.dest.to_string_lossy()
+            )
+        })?;
+    file.write_all(write.content.as_bytes())?;
+
+    let uid = write.attributes.owner.as_deref().map(user_id).transpose()?;
+    let gid = write
+        .attributes
+        .group
+        .as_deref()
+        .map(group_id)
+        .transpose()?;
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(staged, uid, gid).inspect_err(|_| {
+            error!(
+                "failed to change owner of: {}",
+                &write.dest.to_string_lossy()
+            )
+        })?;
+    }
+    file.set_permissions(std::fs::Permissions::from_mode(write.attributes.mode))?;
+
+    std::fs::rename(staged, &write.dest).inspect_err(|_| {
+        error!(
+            "failed to write destination file: {}",
+            &write.dest.to_string_lossy()
+        )
+    })
+}
+
+/// Create a directory with its parents, then apply the mode and ownership to it
+pub fn create_dir(dest: &Path, attributes: &Attributes) -> std::io::Result<()> {
+    std::fs::create_dir_all(dest)
+        .inspect_err(|_| error!("failed to create directory: {}", &dest.to_string_lossy()))?;
+
+    let uid = attributes.owner.as_deref().map(user_id).transpose()?;
+    let gid = attributes.group.as_deref().map(group_id).transpose()?;
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(dest, uid, gid)
+            .inspect_err(|_| error!("failed to change owner of: {}", &dest.to_string_lossy()))?;
+    }
+    std::fs::set_permissions(dest, std::fs::Permissions::from_mode(attributes.mode))
+}
+
+/// Quote a path or argument for `sh`
+fn quote(value: impl AsRef<std::ffi::OsStr>) -> String {
+    format!(
+        "'{}'",
+        value.as_ref().to_string_lossy().replace('\'', r"'\''")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+
+    #[test]
+    fn test_run_direct() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let dest = test_dir.path().join("etc/hosts");
+
+        let mut batch = PrivilegedBatch::new(Escalation::None);
+        batch.write(&dest, "127.0.0.1 localhost\n", &Attributes::new(0o100640));
+        batch.run().unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&dest).unwrap(),
+            "127.0.0.1 localhost\n"
+        );
+        assert_eq!(dest.metadata().unwrap().mode() & 0o7777, 0o640);
+        assert!(!staging_path(&dest).exists());
+    }
+
+    #[test]
+    fn test_run_direct_failure() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        std::fs::write(test_dir.path().join("etc"), "").unwrap();
+
+        let mut batch = PrivilegedBatch::new(Escalation::None);
+        batch.write(
+            &test_dir.path().join("etc/hosts"),
+            "",
+            &Attributes::new(0o644),
+        );
+
+        assert!(batch.run().is_err());
+    }
+
+    #[test]
+    fn test_run_direct_empty_command() {
+        let mut batch = PrivilegedBatch::new(Escalation::None);
+        batch.command(&[]);
+
+        let error = batch.run().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_script() {
+        let mut batch = PrivilegedBatch::new(Escalation::Doas);
+        batch.write(Path::new("/etc/it's.conf"), "", &Attributes::new(0o644));
+        batch.write(
+            Path::new("/etc/sudoers.d/wheel"),
+            "",
+            &Attributes {
+                mode: 0o440,
+                owner: Some("root".into()),
+                group: Some("wheel".into()),
+            },
+        );
+
+        let script = batch.script(&[PathBuf::from("/tmp/0"), PathBuf::from("/tmp/1")]);
+        assert_eq!(
+            script.lines().collect::<Vec<_>>(),
+            vec![
+                "set -e",
+                "staged=",
+                "cleanup() { if [ -n \"$staged\" ]; then rm -f -- \"$staged\"; fi; }",
+                "trap cleanup EXIT",
+                "mkdir -p -- '/etc'",
+                "staged=$(mktemp '/etc/.it'\\''s.conf.spaceconf-new.XXXXXX')",
+                "cp -- '/tmp/0' \"$staged\"",
+                "chmod 644 -- \"$staged\"",
+                "mv -f -- \"$staged\" '/etc/it'\\''s.conf'",
+                "mkdir -p -- '/etc/sudoers.d'",
+                "staged=$(mktemp '/etc/sudoers.d/.wheel.spaceconf-new.XXXXXX')",
+                "cp -- '/tmp/1' \"$staged\"",
+                "chown -- 'root:wheel' \"$staged\"",
+                "chmod 440 -- \"$staged\"",
+                "mv -f -- \"$staged\" '/etc/sudoers.d/wheel'",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_script_removals() {
+        let mut batch = PrivilegedBatch::new(Escalation::Sudo);
+        batch.remove(Path::new("/etc/profile.d/old.sh"));
+
+        assert_eq!(
+            batch.script(&[]),
+            "set -e\nrm -f -- '/etc/profile.d/old.sh'\n"
+        );
+    }
+
+    #[test]
+    fn test_script_commands() {
+        let mut batch = PrivilegedBatch::new(Escalation::Sudo);
+        batch.write(
+            Path::new("/etc/systemd/system/backup.service"),
+            "",
+            &Attributes::new(0o644),
+        );
+        batch.command(&["systemctl".into(), "daemon-reload".into()]);
+
+        let script = batch.script(&[PathBuf::from("/tmp/0")]);
+        assert!(script.ends_with(
+            "mv -f -- \"$staged\" '/etc/systemd/system/backup.service'\n\
+             'systemctl' 'daemon-reload'\n"
+        ));
+    }
+
+    #[test]
+    fn test_script_directories() {
+        let mut batch = PrivilegedBatch::new(Escalation::Sudo);
+        batch.create_dir(
+            Path::new("/srv/backup"),
+            &Attributes {
+                mode: 0o750,
+                owner: Some("backup".into()),
+                group: None,
+            },
+        );
+        batch.remove_dir(Path::new("/srv/backup/old"));
+
+        assert_eq!(
+            batch.script(&[]).lines().collect::<Vec<_>>(),
+            vec![
+                "set -e",
+                "mkdir -p -- '/srv/backup'",
+                "chown -- 'backup' '/srv/backup'",
+                "chmod 750 -- '/srv/backup'",
+                "rm -rf -- '/srv/backup/old'",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_direct_removals() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let dest = test_dir.path().join("old.sh");
+        std::fs::write(&dest, "").unwrap();
+
+        let mut batch = PrivilegedBatch::new(Escalation::None);
+        batch.remove(&dest);
+        batch.remove(&test_dir.path().join("missing.sh"));
+        batch.run().unwrap();
+
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn test_script_runs() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let src = test_dir.path().join("staged");
+        std::fs::write(&src, "secret").unwrap();
+        let dest = test_dir.path().join("etc/app.conf");
+        std::fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        std::fs::write(&dest, "old").unwrap();
+
+        let mut batch = PrivilegedBatch::new(Escalation::Sudo);
+        batch.write(&dest, "", &Attributes::new(0o640));
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(batch.script(&[src]))
+            .status()
+            .unwrap();
+
+        assert!(status.success());
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "secret");
+        assert_eq!(dest.metadata().unwrap().mode() & 0o7777, 0o640);
+        assert_eq!(
+            std::fs::read_dir(dest.parent().unwrap()).unwrap().count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_script_ignores_planted_symlink() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let src = test_dir.path().join("staged");
+        std::fs::write(&src, "secret").unwrap();
+        let victim = test_dir.path().join("victim");
+        std::fs::write(&victim, "untouched").unwrap();
+        let dest = test_dir.path().join("app.conf");
+        std::os::unix::fs::symlink(&victim, staging_path(&dest)).unwrap();
+
+        let mut batch = PrivilegedBatch::new(Escalation::Sudo);
+        batch.write(&dest, "", &Attributes::new(0o644));
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(batch.script(&[src]))
+            .status()
+            .unwrap();
+
+        assert!(status.success());
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "secret");
+        assert_eq!(std::fs::read_to_string(&victim).unwrap(), "untouched");
+    }
+
+    #[test]
+    fn test_user_id() {
+        assert_eq!(user_id("root").unwrap(), 0);
+        assert_eq!(user_id("1000").unwrap(), 1000);
+        assert!(user_id("no-such-user-spaceconf").is_err());
+    }
+}
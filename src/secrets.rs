@@ -0,0 +1,503 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use age::{secrecy::ExposeSecret, x25519::Identity};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+pub type Secrets = HashMap<String, Secret>;
+
+/// A secret value, either stored directly or fetched when a template uses it
+///
+/// ```json
+/// {
+///     "API_KEY": "12345abcde",
+///     "WEATHER_KEY": { "command": "pass show weather/api" },
+///     "GITHUB_TOKEN": { "env": "GITHUB_TOKEN" }
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Secret {
+    Value(String),
+    Command { command: String },
+    Env { env: String },
+}
+
+impl Secret {
+    /// The secret's value. Commands are run at most once per process and their output is cached.
+    ///
+    /// Errors never include the value, so they are safe to print.
+    pub fn resolve(&self) -> std::io::Result<String> {
+        let value = self.fetch()?;
+        remember(&value);
+        Ok(value)
+    }
+
+    fn fetch(&self) -> std::io::Result<String> {
+        match self {
+            Secret::Value(value) => Ok(value.clone()),
+            Secret::Env { env } => std::env::var(env).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("environment variable {} is not set", env),
+                )
+            }),
+            Secret::Command { command } => {
+                static CACHE: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+                let mut cache = CACHE.get_or_init(Default::default).lock().unwrap();
+                if let Some(value) = cache.get(command) {
+                    return Ok(value.clone());
+                }
+
+                let value = run_command(command)?;
+                cache.insert(command.clone(), value.clone());
+                Ok(value)
+            }
+        }
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret::Value(value.to_string())
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret::Value(value)
+    }
+}
+
+/// The mask printed in place of secret values
+pub const MASK: &str = "********";
+
+static SHOW_SECRETS: AtomicBool = AtomicBool::new(false);
+
+fn known_values() -> &'static Mutex<Vec<String>> {
+    static VALUES: OnceLock<Mutex<Vec<String>>> = OnceLock::new();
+    VALUES.get_or_init(Default::default)
+}
+
+/// Track a value so it is masked by [`redact`]. A single character would be masked everywhere
+/// it appears, so it is left out with a warning instead.
+fn remember(value: &str) {
+    if value.is_empty() {
+        return;
+    }
+    if value.chars().count() == 1 {
+        static WARNED: AtomicBool = AtomicBool::new(false);
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            warn!("a secret is a single character and can't be masked in the output");
+        }
+        return;
+    }
+
+    let mut values = known_values().lock().unwrap();
+    if !values.iter().any(|known| known == value) {
+        values.push(value.to_string());
+        // mask longer values first, so a secret containing another isn't partially revealed
+        values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+    }
+}
+
+/// Print secret values as they are instead of masking them
+pub fn show_secrets(show: bool) {
+    SHOW_SECRETS.store(show, Ordering::Relaxed);
+}
+
+/// Mask every secret value loaded so far in `text`, unless [`show_secrets`] was enabled.
+/// Use this for anything printed to the terminal, but never for content written to files.
+pub fn redact(text: &str) -> String {
+    if SHOW_SECRETS.load(Ordering::Relaxed) {
+        return text.to_string();
+    }
+
+    known_values()
+        .lock()
+        .unwrap()
+        .iter()
+        .fold(text.to_string(), |text, value| text.replace(value, MASK))
+}
+
+/// The secrets with every value replaced by `<secret:NAME>`, for output that gets committed,
+/// like snapshots. Commands and environment variables aren't resolved.
+pub fn placeholders(secrets: &Secrets) -> Secrets {
+    secrets
+        .keys()
+        .map(|name| (name.clone(), Secret::Value(format!("<secret:{}>", name))))
+        .collect()
+}
+
+/// Run a secret command with the terminal attached, so password managers can prompt
+fn run_command(command: &str) -> std::io::Result<String> {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .inspect_err(|_| error!("failed to run secret command: {}", command))?;
+
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "secret command `{}` exited with {}",
+            command, output.status
+        )));
+    }
+
+    let value = String::from_utf8(output.stdout).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("secret command `{}` did not output valid UTF-8", command),
+        )
+    })?;
+    Ok(value.trim_end_matches(['\n', '\r']).to_string())
+}
+
+pub const SECRETS_FILE: &str = "secrets.json";
+pub const ENCRYPTED_SECRETS_FILE: &str = "secrets.json.age";
+
+/// The key used to encrypt secrets, kept outside of the repository.
+///
+/// Defaults to `~/.config/spaceconf/key.txt` and can be overridden with `SPACECONF_KEY_FILE`.
+/// The file uses the same format as `age-keygen`.
+pub fn key_file() -> PathBuf {
+    match std::env::var_os("SPACECONF_KEY_FILE") {
+        Some(path) => PathBuf::from(path),
+        None => dirs::config_dir()
+            .unwrap()
+            .join("spaceconf")
+            .join("key.txt"),
+    }
+}
+
+pub fn load_key(key_file: &Path) -> std::io::Result<Identity> {
+    let content = std::fs::read_to_string(key_file)
+        .inspect_err(|_| error!("failed to read key file: {}", &key_file.to_string_lossy()))?;
+
+    content
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("AGE-SECRET-KEY-"))
+        .and_then(|line| Identity::from_str(line).ok())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("no valid key found in {}", key_file.display()),
+            )
+        })
+}
+
+pub fn generate_key(key_file: &Path) -> std::io::Result<Identity> {
+    let identity = Identity::generate();
+
+    std::fs::create_dir_all(key_file.parent().unwrap())?;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(key_file)
+        .inspect_err(|_| error!("failed to create key file: {}", &key_file.to_string_lossy()))?;
+    writeln!(file, "# created: {}", chrono::Local::now().to_rfc3339())?;
+    writeln!(file, "# public key: {}", identity.to_public())?;
+    writeln!(file, "{}", identity.to_string().expose_secret())?;
+
+    Ok(identity)
+}
+
+pub fn encrypt(identity: &Identity, plaintext: &[u8]) -> std::io::Result<Vec<u8>> {
+    age::encrypt_and_armor(&identity.to_public(), plaintext)
+        .map(String::into_bytes)
+        .map_err(std::io::Error::other)
+}
+
+pub fn decrypt(identity: &Identity, ciphertext: &[u8]) -> std::io::Result<Vec<u8>> {
+    age::decrypt(identity, ciphertext)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Load a fixture's secrets from `secrets.json` and `secrets.json.age`.
+///
+/// If both exist they are merged, with the encrypted values taking precedence.
+pub fn load(fixture_dir: &Path, key_file: &Path) -> std::io::Result<Secrets> {
+    let mut secrets = Secrets::new();
+
+    let plain_file = fixture_dir.join(SECRETS_FILE);
+    if plain_file.exists() {
+        let content = std::fs::read_to_string(&plain_file)?;
+        secrets.extend(parse(&content, &plain_file)?);
+    }
+
+    let encrypted_file = fixture_dir.join(ENCRYPTED_SECRETS_FILE);
+    if encrypted_file.exists() {
+        let identity = load_key(key_file)?;
+        let content = decrypt(&identity, &std::fs::read(&encrypted_file)?).inspect_err(|_| {
+            error!(
+                "failed to decrypt secrets: {}",
+                &encrypted_file.to_string_lossy()
+            )
+        })?;
+        secrets.extend(parse(&String::from_utf8_lossy(&content), &encrypted_file)?);
+    }
+
+    for secret in secrets.values() {
+        if let Secret::Value(value) = secret {
+            remember(value);
+        }
+    }
+
+    Ok(secrets)
+}
+
+fn parse(content: &str, path: &Path) -> std::io::Result<Secrets> {
+    serde_json::from_str(content).map_err(|e| {
+        error!("invalid secrets file: {}", &path.to_string_lossy());
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    })
+}
+
+/// Decrypt a fixture's secrets to a private temporary file, open it in `editor` and encrypt
+/// the result back into `secrets.json.age`.
+///
+/// A plaintext `secrets.json` is used as the starting point if there are no encrypted secrets
+/// yet, and a key is generated if there isn't one.
+pub fn edit(fixture_dir: &Path, key_file: &Path, editor: &str) -> std::io::Result<()> {
+    let identity = if key_file.exists() {
+        load_key(key_file)?
+    } else {
+        let identity = generate_key(key_file)?;
+        println!(
+            "Generated a new key at {}, copy it to your other machines to decrypt your secrets",
+            key_file.display()
+        );
+        identity
+    };
+
+    let encrypted_file = fixture_dir.join(ENCRYPTED_SECRETS_FILE);
+    let plain_file = fixture_dir.join(SECRETS_FILE);
+    let original = if encrypted_file.exists() {
+        decrypt(&identity, &std::fs::read(&encrypted_file)?)?
+    } else if plain_file.exists() {
+        std::fs::read(&plain_file)?
+    } else {
+        b"{\n}\n".to_vec()
+    };
+
+    let temp_dir = PrivateTempDir::new()?;
+    let temp_file = temp_dir.path().join(SECRETS_FILE);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp_file)?
+        .write_all(&original)?;
+
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&temp_file)
+        .status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "editor exited with {}, secrets were not changed",
+            status
+        )));
+    }
+
+    let edited = std::fs::read(&temp_file)?;
+    parse(&String::from_utf8_lossy(&edited), &temp_file)?;
+
+    if edited == original && encrypted_file.exists() {
+        println!("Secrets unchanged");
+        return Ok(());
+    }
+
+    std::fs::write(&encrypted_file, encrypt(&identity, &edited)?)?;
+    println!("Saved {}", encrypted_file.display());
+
+    if plain_file.exists() {
+        warn!(
+            "{} still contains plaintext secrets, remove it once you have checked {}",
+            plain_file.display(),
+            encrypted_file.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// A temporary directory only readable by the current user, removed when dropped
+pub struct PrivateTempDir(PathBuf);
+
+impl PrivateTempDir {
+    pub fn new() -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("spaceconf-{}", uuid::Uuid::new_v4()));
+        std::fs::DirBuilder::new().mode(0o700).create(&path)?;
+        Ok(Self(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for PrivateTempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_and_load_key() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let key_file = test_dir.path().join("spaceconf/key.txt");
+
+        let identity = generate_key(&key_file).unwrap();
+        let loaded = load_key(&key_file).unwrap();
+
+        assert_eq!(
+            identity.to_public().to_string(),
+            loaded.to_public().to_string()
+        );
+    }
+
+    #[test]
+    fn test_load_encrypted_secrets() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let key_file = test_dir.path().join("key.txt");
+        let identity = generate_key(&key_file).unwrap();
+
+        std::fs::write(
+            test_dir.path().join(SECRETS_FILE),
+            r#"{"API_KEY": "plain", "USER": "me"}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            test_dir.path().join(ENCRYPTED_SECRETS_FILE),
+            encrypt(&identity, br#"{"API_KEY": "12345abcde"}"#).unwrap(),
+        )
+        .unwrap();
+
+        let secrets = load(test_dir.path(), &key_file).unwrap();
+
+        assert_eq!(secrets.get("API_KEY"), Some(&"12345abcde".into()));
+        assert_eq!(secrets.get("USER"), Some(&"me".into()));
+    }
+
+    #[test]
+    fn test_load_encrypted_secrets_wrong_key() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let identity = generate_key(&test_dir.path().join("key.txt")).unwrap();
+        let other_key = test_dir.path().join("other.txt");
+        generate_key(&other_key).unwrap();
+
+        std::fs::write(
+            test_dir.path().join(ENCRYPTED_SECRETS_FILE),
+            encrypt(&identity, br#"{"API_KEY": "12345abcde"}"#).unwrap(),
+        )
+        .unwrap();
+
+        assert!(load(test_dir.path(), &other_key).is_err());
+    }
+
+    #[test]
+    fn test_edit_secrets() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let key_file = test_dir.path().join("key.txt");
+
+        let editor = r#"printf '{"API_KEY": "12345abcde"}' >"#;
+        edit(test_dir.path(), &key_file, editor).unwrap();
+
+        let encrypted =
+            std::fs::read_to_string(test_dir.path().join(ENCRYPTED_SECRETS_FILE)).unwrap();
+        assert!(!encrypted.contains("12345abcde"));
+
+        let secrets = load(test_dir.path(), &key_file).unwrap();
+        assert_eq!(secrets.get("API_KEY"), Some(&"12345abcde".into()));
+    }
+
+    #[test]
+    fn test_edit_invalid_json() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let key_file = test_dir.path().join("key.txt");
+
+        let editor = "printf 'not json' >";
+        assert!(edit(test_dir.path(), &key_file, editor).is_err());
+        assert!(!test_dir.path().join(ENCRYPTED_SECRETS_FILE).exists());
+    }
+
+    #[test]
+    fn test_resolve_command_secret() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let counter = test_dir.path().join("runs");
+
+        let secret: Secret = serde_json::from_str(&format!(
+            r#"{{"command": "echo run >> {}; echo 12345abcde"}}"#,
+            counter.display()
+        ))
+        .unwrap();
+
+        assert_eq!(secret.resolve().unwrap(), "12345abcde");
+        assert_eq!(secret.resolve().unwrap(), "12345abcde");
+        assert_eq!(std::fs::read_to_string(counter).unwrap(), "run\n");
+    }
+
+    #[test]
+    fn test_resolve_failed_command_secret() {
+        let secret = Secret::Command {
+            command: "echo abcde | tr a-e v-z; exit 1".into(),
+        };
+
+        let error = secret.resolve().unwrap_err().to_string();
+        assert!(!error.contains("vwxyz"));
+        assert!(error.contains("exited with"));
+    }
+
+    #[test]
+    fn test_resolve_env_secret() {
+        let secret: Secret = serde_json::from_str(r#"{"env": "SPACECONF_TEST_SECRET"}"#).unwrap();
+        assert!(secret.resolve().is_err());
+
+        std::env::set_var("SPACECONF_TEST_SECRET", "12345abcde");
+        assert_eq!(secret.resolve().unwrap(), "12345abcde");
+    }
+
+    #[test]
+    fn test_redact() {
+        let secret = Secret::Value("12345abcde-long".into());
+        secret.resolve().unwrap();
+        Secret::Value("12345abcde".into()).resolve().unwrap();
+
+        assert_eq!(
+            redact("--api-key 12345abcde-long\n--key=12345abcde"),
+            "--api-key ********\n--key=********"
+        );
+    }
+
+    #[test]
+    fn test_redact_short_values() {
+        Secret::Value("4821".into()).resolve().unwrap();
+        Secret::Value("7".into()).resolve().unwrap();
+
+        assert_eq!(
+            redact("pin = 4821\nretries = 7"),
+            "pin = ********\nretries = 7"
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use std::path::Path;
+
+use crate::fixture::{File, Format, Strategy};
+
+mod block;
+mod merge;
+mod patch;
+
+/// The full content the destination should have after applying the rendered source
+pub fn apply(
+    file: &File,
+    fixture: &str,
+    dest: &Path,
+    rendered: &str,
+    current: Option<&str>,
+) -> std::io::Result<String> {
+    match file.strategy {
+        Strategy::Replace => Ok(rendered.to_string()),
+        Strategy::Block => block::replace(
+            current.unwrap_or_default(),
+            &block::Markers::new(file, fixture),
+            rendered,
+        ),
+        Strategy::Merge => merge::merge(format(file, dest)?, rendered, current),
+        Strategy::Patch => patch::apply(rendered, current.unwrap_or_default()),
+    }
+}
+
+/// The part of the destination spaceconf manages, as it is now and as it should be, so diffs
+/// don't show the parts that are left alone
+pub fn managed(
+    file: &File,
+    fixture: &str,
+    dest: &Path,
+    rendered: &str,
+    current: Option<&str>,
+) -> std::io::Result<(String, String)> {
+    match file.strategy {
+        Strategy::Replace => Ok((
+            current.unwrap_or_default().to_string(),
+            rendered.to_string(),
+        )),
+        Strategy::Block => {
+            let markers = block::Markers::new(file, fixture);
+            let existing = block::extract(current.unwrap_or_default(), &markers)?;
+            Ok((
+                existing.unwrap_or_default().to_string(),
+                rendered.to_string(),
+            ))
+        }
+        Strategy::Merge => merge::managed(format(file, dest)?, rendered, current),
+        Strategy::Patch => {
+            let current = current.unwrap_or_default();
+            Ok((current.to_string(), patch::apply(rendered, current)?))
+        }
+    }
+}
+
+/// The destination with spaceconf's changes undone, or `None` if the whole file should be
+/// restored from its backup instead
+pub fn revert(
+    file: &File,
+    fixture: &str,
+    rendered: &str,
+    current: &str,
+) -> std::io::Result<Option<String>> {
+    match file.strategy {
+        Strategy::Replace | Strategy::Merge => Ok(None),
+        Strategy::Block => block::remove(current, &block::Markers::new(file, fixture)).map(Some),
+        Strategy::Patch => patch::revert(rendered, current).map(Some),
+    }
+}
+
+/// A short description of how the source relates to the destination, for strategies where
+/// that isn't obvious from a diff
+pub fn status(
+    file: &File,
+    rendered: &str,
+    current: Option<&str>,
+) -> std::io::Result<Option<String>> {
+    match file.strategy {
+        Strategy::Patch => patch::status(rendered, current.unwrap_or_default())
+            .map(|status| Some(status.to_string())),
+        _ => Ok(None),
+    }
+}
+
+fn format(file: &File, dest: &Path) -> std::io::Result<Format> {
+    file.format.or_else(|| Format::detect(dest)).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "cannot detect the format of {:?}, set \"format\" to json, toml, yaml or ini",
+                dest
+            ),
+        )
+    })
+}
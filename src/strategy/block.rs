@@ -0,0 +1,156 @@
+use std::ops::Range;
+
+use crate::fixture::File;
+
+/// The comment lines around a fixture's block, e.g. `# BEGIN spaceconf:hosts`
+pub struct Markers {
+    begin: String,
+    end: String,
+}
+
+impl Markers {
+    pub fn new(file: &File, fixture: &str) -> Self {
+        let comment = file.comment.as_deref().unwrap_or("#");
+        Self {
+            begin: format!("{} BEGIN spaceconf:{}", comment, fixture),
+            end: format!("{} END spaceconf:{}", comment, fixture),
+        }
+    }
+}
+
+/// Byte range of the block including its markers, and of its body
+fn find(content: &str, markers: &Markers) -> std::io::Result<Option<(Range<usize>, Range<usize>)>> {
+    let mut offset = 0;
+    let mut begin = None;
+    for line in content.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+
+        match begin {
+            None if line.trim() == markers.begin => begin = Some((start, offset)),
+            Some((block_start, body_start)) if line.trim() == markers.end => {
+                return Ok(Some((block_start..offset, body_start..start)));
+            }
+            _ => {}
+        }
+    }
+
+    match begin {
+        Some(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("found `{}` without `{}`", markers.begin, markers.end),
+        )),
+        None => Ok(None),
+    }
+}
+
+/// The body of the block, if the content has one
+pub fn extract<'a>(content: &'a str, markers: &Markers) -> std::io::Result<Option<&'a str>> {
+    Ok(find(content, markers)?.map(|(_, body)| &content[body]))
+}
+
+/// Update the block in the content, or append it if there isn't one
+pub fn replace(content: &str, markers: &Markers, body: &str) -> std::io::Result<String> {
+    let mut block = format!("{}\n{}", markers.begin, body);
+    if !body.is_empty() && !body.ends_with('\n') {
+        block.push('\n');
+    }
+    block += &markers.end;
+    block.push('\n');
+
+    match find(content, markers)? {
+        Some((range, _)) => Ok(format!(
+            "{}{}{}",
+            &content[..range.start],
+            block,
+            &content[range.end..]
+        )),
+        None if content.is_empty() || content.ends_with('\n') => {
+            Ok(format!("{}{}", content, block))
+        }
+        None => Ok(format!("{}\n{}", content, block)),
+    }
+}
+
+/// Remove the block and its markers, leaving everything else as it is
+pub fn remove(content: &str, markers: &Markers) -> std::io::Result<String> {
+    match find(content, markers)? {
+        Some((range, _)) => Ok(format!(
+            "{}{}",
+            &content[..range.start],
+            &content[range.end..]
+        )),
+        None => Ok(content.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn markers(comment: &str) -> Markers {
+        Markers {
+            begin: format!("{} BEGIN spaceconf:hosts", comment),
+            end: format!("{} END spaceconf:hosts", comment),
+        }
+    }
+
+    #[test]
+    fn test_replace_appends_block() {
+        let content = "127.0.0.1 localhost";
+
+        let updated = replace(content, &markers("#"), "10.0.0.2 nas\n").unwrap();
+        assert_eq!(
+            updated,
+            "127.0.0.1 localhost\n# BEGIN spaceconf:hosts\n10.0.0.2 nas\n# END spaceconf:hosts\n"
+        );
+    }
+
+    #[test]
+    fn test_replace_updates_block() {
+        let content = "\
+127.0.0.1 localhost
+# BEGIN spaceconf:hosts
+10.0.0.2 nas
+# END spaceconf:hosts
+::1 localhost
+";
+
+        let updated = replace(content, &markers("#"), "10.0.0.3 nas").unwrap();
+        assert_eq!(
+            updated,
+            "\
+127.0.0.1 localhost
+# BEGIN spaceconf:hosts
+10.0.0.3 nas
+# END spaceconf:hosts
+::1 localhost
+"
+        );
+        assert_eq!(
+            extract(&updated, &markers("#")).unwrap(),
+            Some("10.0.0.3 nas\n")
+        );
+        assert_eq!(
+            remove(&updated, &markers("#")).unwrap(),
+            "127.0.0.1 localhost\n::1 localhost\n"
+        );
+    }
+
+    #[test]
+    fn test_comment_syntax() {
+        let updated = replace("", &markers("//"), "set number").unwrap();
+        assert_eq!(
+            updated,
+            "// BEGIN spaceconf:hosts\nset number\n// END spaceconf:hosts\n"
+        );
+        assert_eq!(extract(&updated, &markers("#")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_missing_end_marker() {
+        let content = "# BEGIN spaceconf:hosts\n10.0.0.2 nas\n";
+
+        assert!(replace(content, &markers("#"), "").is_err());
+    }
+}
@@ -0,0 +1,327 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{fixture::Format, vars::Vars};
+
+/// The destination with the rendered source merged into it. The destination is returned as it
+/// is if the merge doesn't change any values, so its formatting isn't rewritten needlessly.
+pub fn merge(format: Format, rendered: &str, current: Option<&str>) -> std::io::Result<String> {
+    let document = parse_current(format, current)?;
+    let partial = parse(format, rendered, "source")?;
+    let mut merged = document.clone();
+    crate::vars::merge(&mut merged, partial.clone());
+
+    match current {
+        Some(current) if merged == document => Ok(current.to_string()),
+        // edited in place, so comments and the order of keys are kept
+        Some(current) if format == Format::Toml && !current.trim().is_empty() => {
+            merge_toml(current, &partial)
+        }
+        _ => serialize(format, &merged),
+    }
+}
+
+fn merge_toml(current: &str, partial: &Vars) -> std::io::Result<String> {
+    let invalid = |e: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("failed to write Toml: {}", e),
+        )
+    };
+
+    let mut document: toml_edit::DocumentMut = current
+        .parse()
+        .map_err(|e: toml_edit::TomlError| invalid(e.to_string()))?;
+    merge_table(document.as_table_mut(), partial).map_err(invalid)?;
+    Ok(document.to_string())
+}
+
+fn merge_table(table: &mut dyn toml_edit::TableLike, partial: &Vars) -> Result<(), String> {
+    for (key, value) in partial {
+        let existing = table.get_mut(key).and_then(|item| item.as_table_like_mut());
+        if let (Some(existing), Value::Object(partial)) = (existing, value) {
+            merge_table(existing, partial)?;
+            continue;
+        }
+
+        let mut item = toml_item(value)?;
+        // a comment after the old value stays with the new one
+        let decor = table
+            .get(key)
+            .and_then(|item| item.as_value())
+            .map(|value| value.decor());
+        if let (Some(decor), Some(value)) = (decor.cloned(), item.as_value_mut()) {
+            *value.decor_mut() = decor;
+        }
+        table.insert(key, item);
+    }
+
+    Ok(())
+}
+
+/// Objects become tables, everything else a value
+fn toml_item(value: &Value) -> Result<toml_edit::Item, String> {
+    match value {
+        Value::Object(partial) => {
+            let mut table = toml_edit::Table::new();
+            merge_table(&mut table, partial)?;
+            Ok(toml_edit::Item::Table(table))
+        }
+        value => value
+            .serialize(toml_edit::ser::ValueSerializer::new())
+            .map(toml_edit::Item::Value)
+            .map_err(|e| e.to_string()),
+    }
+}
+
+/// The values the source sets, as they are in the destination now and after merging
+pub fn managed(
+    format: Format,
+    rendered: &str,
+    current: Option<&str>,
+) -> std::io::Result<(String, String)> {
+    let document = parse_current(format, current)?;
+    let partial = parse(format, rendered, "source")?;
+
+    let existing = project(&document, &partial);
+    let mut desired = existing.clone();
+    crate::vars::merge(&mut desired, partial);
+
+    Ok((serialize(format, &existing)?, serialize(format, &desired)?))
+}
+
+/// The parts of `document` at the keys `partial` sets
+fn project(document: &Vars, partial: &Vars) -> Vars {
+    let mut projected = Vars::new();
+    for (key, value) in partial {
+        match (document.get(key), value) {
+            (Some(Value::Object(document)), Value::Object(partial)) => {
+                projected.insert(key.clone(), Value::Object(project(document, partial)));
+            }
+            (Some(existing), _) => {
+                projected.insert(key.clone(), existing.clone());
+            }
+            (None, _) => {}
+        }
+    }
+
+    projected
+}
+
+fn parse_current(format: Format, current: Option<&str>) -> std::io::Result<Vars> {
+    match current {
+        Some(current) if !current.trim().is_empty() => parse(format, current, "destination"),
+        _ => Ok(Vars::new()),
+    }
+}
+
+fn parse(format: Format, content: &str, what: &str) -> std::io::Result<Vars> {
+    let parsed = match format {
+        // JSON5 also covers the comments and trailing commas of JSONC files like VS Code's
+        Format::Json => json5::from_str(content).map_err(|e| e.to_string()),
+        Format::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+        Format::Yaml => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+        Format::Ini => parse_ini(content),
+    };
+
+    parsed.map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid {:?} in {}: {}", format, what, e),
+        )
+    })
+}
+
+fn serialize(format: Format, document: &Vars) -> std::io::Result<String> {
+    let serialized = match format {
+        Format::Json => serde_json::to_string_pretty(document)
+            .map(|json| json + "\n")
+            .map_err(|e| e.to_string()),
+        Format::Toml => toml::to_string_pretty(document).map_err(|e| e.to_string()),
+        Format::Yaml => serde_yaml::to_string(document).map_err(|e| e.to_string()),
+        Format::Ini => Ok(serialize_ini(document)),
+    };
+
+    serialized.map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("failed to write {:?}: {}", format, e),
+        )
+    })
+}
+
+/// Sections become objects, keys before the first section are top-level values
+fn parse_ini(content: &str) -> Result<Vars, String> {
+    let ini = ini::Ini::load_from_str(content).map_err(|e| e.to_string())?;
+
+    let mut document = Vars::new();
+    for (section, properties) in ini.iter() {
+        let values = properties
+            .iter()
+            .map(|(key, value)| (key.to_string(), Value::String(value.to_string())));
+
+        match section {
+            Some(section) => {
+                let entry = document
+                    .entry(section)
+                    .or_insert_with(|| Value::Object(Vars::new()));
+                if let Value::Object(entry) = entry {
+                    entry.extend(values);
+                }
+            }
+            None => document.extend(values),
+        }
+    }
+
+    Ok(document)
+}
+
+fn serialize_ini(document: &Vars) -> String {
+    let value = |value: &Value| match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    };
+
+    let mut output = String::new();
+    for (key, entry) in document {
+        if !entry.is_object() {
+            output += &format!("{}={}\n", key, value(entry));
+        }
+    }
+    for (section, entry) in document {
+        if let Value::Object(properties) = entry {
+            if !output.is_empty() {
+                output.push('\n');
+            }
+            output += &format!("[{}]\n", section);
+            for (key, entry) in properties {
+                output += &format!("{}={}\n", key, value(entry));
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_json() {
+        let current = r#"{
+    "editor.fontSize": 12,
+    "window.zoomLevel": 1,
+    "files.exclude": { "**/.git": true }
+}"#;
+        let rendered = r#"{"editor.fontSize": 14, "files.exclude": {"**/target": true}}"#;
+
+        let merged = merge(Format::Json, rendered, Some(current)).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&merged).unwrap(),
+            serde_json::json!({
+                "editor.fontSize": 14,
+                "window.zoomLevel": 1,
+                "files.exclude": { "**/.git": true, "**/target": true }
+            })
+        );
+
+        // already merged, the file is left as it is
+        assert_eq!(
+            merge(Format::Json, rendered, Some(&merged)).unwrap(),
+            merged
+        );
+    }
+
+    #[test]
+    fn test_merge_toml() {
+        let current = "[ui]\ntheme = \"light\"\nfont = \"Fira Code\"\n";
+
+        let merged = merge(Format::Toml, "[ui]\ntheme = \"dark\"\n", Some(current)).unwrap();
+        assert_eq!(merged, "[ui]\ntheme = \"dark\"\nfont = \"Fira Code\"\n");
+    }
+
+    #[test]
+    fn test_merge_toml_keeps_comments() {
+        let current = "\
+# managed by hand
+[ui]
+theme = \"light\" # until the dark one is fixed
+font = \"Fira Code\"
+";
+
+        let merged = merge(
+            Format::Toml,
+            "[ui]\ntheme = \"dark\"\n[editor]\ntab_size = 4\n",
+            Some(current),
+        )
+        .unwrap();
+        assert_eq!(
+            merged,
+            "\
+# managed by hand
+[ui]
+theme = \"dark\" # until the dark one is fixed
+font = \"Fira Code\"
+
+[editor]
+tab_size = 4
+"
+        );
+    }
+
+    #[test]
+    fn test_merge_jsonc() {
+        let current = r#"{
+    // set by the settings UI
+    "editor.fontSize": 12,
+    /* kept */ "window.zoomLevel": 1,
+    "files.exclude": { "**/.git": true, },
+}"#;
+
+        let merged = merge(Format::Json, r#"{"editor.fontSize": 14}"#, Some(current)).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&merged).unwrap(),
+            serde_json::json!({
+                "editor.fontSize": 14,
+                "window.zoomLevel": 1,
+                "files.exclude": { "**/.git": true }
+            })
+        );
+
+        // nothing to change, so the comments stay
+        assert_eq!(
+            merge(Format::Json, r#"{"editor.fontSize": 12}"#, Some(current)).unwrap(),
+            current
+        );
+    }
+
+    #[test]
+    fn test_merge_yaml() {
+        let merged = merge(Format::Yaml, "font:\n  size: 11\n", None).unwrap();
+        assert_eq!(merged, "font:\n  size: 11\n");
+    }
+
+    #[test]
+    fn test_merge_ini() {
+        let current = "verbose=1\n\n[General]\nlanguage=en\ntheme=light\n";
+
+        let merged = merge(Format::Ini, "[General]\ntheme=dark\n", Some(current)).unwrap();
+        assert_eq!(merged, "verbose=1\n\n[General]\nlanguage=en\ntheme=dark\n");
+    }
+
+    #[test]
+    fn test_managed() {
+        let current = r#"{"editor.fontSize": 12, "window.zoomLevel": 1}"#;
+
+        let (existing, desired) =
+            managed(Format::Json, r#"{"editor.fontSize": 14}"#, Some(current)).unwrap();
+        assert_eq!(existing, "{\n  \"editor.fontSize\": 12\n}\n");
+        assert_eq!(desired, "{\n  \"editor.fontSize\": 14\n}\n");
+    }
+
+    #[test]
+    fn test_invalid_destination() {
+        assert!(merge(Format::Json, "{}", Some("{ not json")).is_err());
+    }
+}
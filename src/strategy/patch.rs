@@ -0,0 +1,132 @@
+use diffy::Patch;
+
+/// How a patch relates to the destination
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Applied,
+    Clean,
+    Conflict,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Applied => write!(f, "already applied"),
+            Status::Clean => write!(f, "applies cleanly"),
+            Status::Conflict => write!(f, "conflict"),
+        }
+    }
+}
+
+fn parse(patch: &str) -> std::io::Result<Patch<'_, str>> {
+    Patch::from_str(patch).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid patch: {}", e),
+        )
+    })
+}
+
+pub fn status(patch: &str, current: &str) -> std::io::Result<Status> {
+    let patch = parse(patch)?;
+
+    // checked first, since a hunk that only adds lines can also apply forward a second time
+    if let Ok(original) = diffy::apply(current, &patch.reverse()) {
+        if diffy::apply(&original, &patch).is_ok_and(|patched| patched == current) {
+            return Ok(Status::Applied);
+        }
+    }
+
+    if diffy::apply(current, &patch).is_ok() {
+        Ok(Status::Clean)
+    } else {
+        Ok(Status::Conflict)
+    }
+}
+
+/// The destination with the patch applied, unchanged if it already is
+pub fn apply(patch: &str, current: &str) -> std::io::Result<String> {
+    match status(patch, current)? {
+        Status::Applied => Ok(current.to_string()),
+        Status::Clean => diffy::apply(current, &parse(patch)?).map_err(std::io::Error::other),
+        Status::Conflict => Err(conflict()),
+    }
+}
+
+/// The destination with the patch reverse-applied, unchanged if it isn't applied
+pub fn revert(patch: &str, current: &str) -> std::io::Result<String> {
+    match status(patch, current)? {
+        Status::Applied => {
+            diffy::apply(current, &parse(patch)?.reverse()).map_err(std::io::Error::other)
+        }
+        Status::Clean => Ok(current.to_string()),
+        Status::Conflict => Err(conflict()),
+    }
+}
+
+fn conflict() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "patch does not apply to the destination, it was changed upstream",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str = "\
+#!/hint/bash
+CARCH=\"x86_64\"
+#MAKEFLAGS=\"-j2\"
+BUILDENV=(!distcc color !ccache check !sign)
+";
+
+    const PATCH: &str = "\
+--- a/makepkg.conf
++++ b/makepkg.conf
+@@ -1,4 +1,4 @@
+ #!/hint/bash
+ CARCH=\"x86_64\"
+-#MAKEFLAGS=\"-j2\"
++MAKEFLAGS=\"-j16\"
+ BUILDENV=(!distcc color !ccache check !sign)
+";
+
+    #[test]
+    fn test_apply_and_revert() {
+        assert_eq!(status(PATCH, ORIGINAL).unwrap(), Status::Clean);
+
+        let patched = apply(PATCH, ORIGINAL).unwrap();
+        assert!(patched.contains("MAKEFLAGS=\"-j16\""));
+        assert_eq!(status(PATCH, &patched).unwrap(), Status::Applied);
+        assert_eq!(apply(PATCH, &patched).unwrap(), patched);
+
+        assert_eq!(revert(PATCH, &patched).unwrap(), ORIGINAL);
+    }
+
+    #[test]
+    fn test_append_only() {
+        let patch = "\
+--- a/file
++++ b/file
+@@ -1,2 +1,3 @@
+ a
+ b
++c
+";
+
+        assert_eq!(status(patch, "a\nb\n").unwrap(), Status::Clean);
+        assert_eq!(status(patch, "a\nb\nc\n").unwrap(), Status::Applied);
+        assert_eq!(apply(patch, "a\nb\nc\n").unwrap(), "a\nb\nc\n");
+        assert_eq!(revert(patch, "a\nb\nc\n").unwrap(), "a\nb\n");
+    }
+
+    #[test]
+    fn test_conflict() {
+        let upstream = ORIGINAL.replace("#MAKEFLAGS=\"-j2\"", "#MAKEFLAGS=\"-j4\"");
+
+        assert_eq!(status(PATCH, &upstream).unwrap(), Status::Conflict);
+        assert!(apply(PATCH, &upstream).is_err());
+    }
+}
@@ -0,0 +1,374 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use log::error;
+
+use crate::{
+    fixture::{Scope, SystemdSetup, Unit},
+    ops::apply::{backup_file, get_backup_filename, restore_file},
+    privilege::{Attributes, PrivilegedBatch},
+    template::Renderer,
+};
+
+/// Where unit files of the scope are installed
+pub fn unit_dir(scope: Scope) -> PathBuf {
+    match scope {
+        Scope::User => dirs::config_dir().unwrap().join("systemd/user"),
+        Scope::System => PathBuf::from("/etc/systemd/system"),
+    }
+}
+
+/// Runs `systemctl` for one scope, or only records the commands it would run so fixtures can be
+/// tested without a running systemd
+pub struct Systemctl {
+    scope: Scope,
+    dry_run: bool,
+    pub recorded: Vec<String>,
+}
+
+impl Systemctl {
+    pub fn new(scope: Scope, dry_run: bool) -> Self {
+        Self {
+            scope,
+            dry_run,
+            recorded: Vec::new(),
+        }
+    }
+
+    fn command(&self, args: &[&str]) -> Vec<String> {
+        let mut command = vec!["systemctl".to_string()];
+        if self.scope == Scope::User {
+            command.push("--user".to_string());
+        }
+        command.extend(args.iter().map(|arg| arg.to_string()));
+        command
+    }
+
+    /// Whether a check like `is-enabled` succeeds for the unit, or `None` if it is unknown
+    /// because systemctl isn't being run
+    fn check(&self, check: &str, unit: &str) -> Option<bool> {
+        if self.dry_run {
+            return None;
+        }
+
+        let command = self.command(&[check, "--quiet", unit]);
+        Command::new(&command[0])
+            .args(&command[1..])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .ok()
+            .map(|status| status.success())
+    }
+
+    /// Run a command now for user units, or queue it after the unit files in the privileged
+    /// batch for system units
+    fn run(&mut self, args: &[&str], batch: &mut PrivilegedBatch) -> std::io::Result<()> {
+        let command = self.command(args);
+        if self.dry_run {
+            println!("Would run `{}`", command.join(" "));
+            self.recorded.push(command.join(" "));
+            return Ok(());
+        }
+
+        println!("Running `{}`", command.join(" "));
+        if self.scope == Scope::System {
+            batch.command(&command);
+            return Ok(());
+        }
+
+        let status = Command::new(&command[0])
+            .args(&command[1..])
+            .status()
+            .inspect_err(|_| error!("failed to run {}", &command[0]))?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "`{}` exited with {}",
+                command.join(" "),
+                status
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Install the unit files, reload the daemon if any of them changed, and bring each unit to its
+/// configured enabled and started state. Unit files that are replaced are backed up into
+/// `backup_dir`, unless it is `None`.
+pub fn apply(
+    setup: &SystemdSetup,
+    unit_dir: &Path,
+    backup_dir: Option<&Path>,
+    renderer: &Renderer,
+    systemctl: &mut Systemctl,
+    batch: &mut PrivilegedBatch,
+) -> std::io::Result<()> {
+    let mut changed = Vec::new();
+    for unit in &setup.units {
+        if install(unit, unit_dir, backup_dir, setup.scope, renderer, batch)? {
+            changed.push(unit.name());
+        }
+    }
+
+    if !changed.is_empty() {
+        systemctl.run(&["daemon-reload"], batch)?;
+    }
+
+    for unit in &setup.units {
+        let name = unit.name();
+        match unit.enabled {
+            Some(true) if systemctl.check("is-enabled", &name) != Some(true) => {
+                systemctl.run(&["enable", &name], batch)?
+            }
+            Some(false) if systemctl.check("is-enabled", &name) != Some(false) => {
+                systemctl.run(&["disable", &name], batch)?
+            }
+            _ => {}
+        }
+
+        match unit.started {
+            // restarting also starts a stopped unit
+            Some(true) if changed.contains(&name) => systemctl.run(&["restart", &name], batch)?,
+            Some(true) if systemctl.check("is-active", &name) != Some(true) => {
+                systemctl.run(&["start", &name], batch)?
+            }
+            Some(false) if systemctl.check("is-active", &name) != Some(false) => {
+                systemctl.run(&["stop", &name], batch)?
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Stop and disable the units that were started and enabled, then restore the unit files they
+/// replaced from their backups, or remove them
+pub fn revert(
+    setup: &SystemdSetup,
+    unit_dir: &Path,
+    backup_dir: &Path,
+    systemctl: &mut Systemctl,
+    batch: &mut PrivilegedBatch,
+) -> std::io::Result<()> {
+    let mut changed = false;
+    for unit in &setup.units {
+        let name = unit.name();
+        if unit.started == Some(true) {
+            systemctl.run(&["stop", &name], batch)?;
+        }
+        if unit.enabled == Some(true) {
+            systemctl.run(&["disable", &name], batch)?;
+        }
+
+        let dest = unit_dir.join(&name);
+        if get_backup_filename(backup_dir, &dest)
+            .symlink_metadata()
+            .is_ok()
+        {
+            println!("Restoring {:?}", dest);
+            let root = (setup.scope == Scope::System).then_some(&mut *batch);
+            restore_file(backup_dir, &dest, root)?;
+            changed = true;
+            continue;
+        }
+
+        if !dest.exists() {
+            continue;
+        }
+        match setup.scope {
+            Scope::System => batch.remove(&dest),
+            Scope::User => std::fs::remove_file(&dest).inspect_err(|_| {
+                error!("failed to remove unit file: {}", &dest.to_string_lossy())
+            })?,
+        }
+        println!("Removing {:?}", dest);
+        changed = true;
+    }
+
+    if changed {
+        systemctl.run(&["daemon-reload"], batch)?;
+    }
+
+    Ok(())
+}
+
+/// Render a unit file into the unit directory, returning whether it changed
+fn install(
+    unit: &Unit,
+    unit_dir: &Path,
+    backup_dir: Option<&Path>,
+    scope: Scope,
+    renderer: &Renderer,
+    batch: &mut PrivilegedBatch,
+) -> std::io::Result<bool> {
+    let input = std::fs::read_to_string(&unit.src)
+        .inspect_err(|_| error!("failed to read unit file: {}", &unit.src.to_string_lossy()))?;
+    let content = renderer
+        .render_file(&unit.src, &input)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    let dest = unit_dir.join(unit.name());
+    if std::fs::read_to_string(&dest).is_ok_and(|current| current == content) {
+        println!("{} is up to date", dest.to_string_lossy());
+        return Ok(false);
+    }
+
+    if let Some(backup_dir) = backup_dir {
+        backup_file(backup_dir, &dest)?;
+    }
+
+    match scope {
+        Scope::System => batch.write(&dest, &content, &Attributes::new(0o644)),
+        Scope::User => {
+            std::fs::create_dir_all(unit_dir).inspect_err(|_| {
+                error!(
+                    "failed to create parent directory(s): {}",
+                    &unit_dir.to_string_lossy()
+                )
+            })?;
+            std::fs::write(&dest, &content).inspect_err(|_| {
+                error!("failed to write unit file: {}", &dest.to_string_lossy())
+            })?;
+        }
+    }
+    println!("Installing {:?}", dest);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{host::Host, privilege::Escalation, vars::Vars};
+
+    fn setup(src: PathBuf) -> SystemdSetup {
+        SystemdSetup {
+            scope: Scope::User,
+            units: vec![Unit {
+                src,
+                enabled: Some(true),
+                started: Some(true),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let src = test_dir.path().join("syncthing.service");
+        std::fs::write(&src, "[Service]\nExecStart=/usr/bin/syncthing\n").unwrap();
+        let unit_dir = test_dir.path().join("systemd/user");
+        let backup_dir = test_dir.path().join("backup");
+        let renderer = Renderer::new(&Host::current(), &Vars::new(), false);
+        let mut batch = PrivilegedBatch::new(Escalation::None);
+
+        let mut systemctl = Systemctl::new(Scope::User, true);
+        apply(
+            &setup(src.clone()),
+            &unit_dir,
+            Some(&backup_dir),
+            &renderer,
+            &mut systemctl,
+            &mut batch,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(unit_dir.join("syncthing.service")).unwrap(),
+            "[Service]\nExecStart=/usr/bin/syncthing\n"
+        );
+        assert_eq!(
+            systemctl.recorded,
+            vec![
+                "systemctl --user daemon-reload",
+                "systemctl --user enable syncthing.service",
+                "systemctl --user restart syncthing.service",
+            ]
+        );
+
+        // unchanged units are not reloaded or restarted
+        let mut systemctl = Systemctl::new(Scope::User, true);
+        apply(
+            &setup(src.clone()),
+            &unit_dir,
+            Some(&backup_dir),
+            &renderer,
+            &mut systemctl,
+            &mut batch,
+        )
+        .unwrap();
+        assert_eq!(
+            systemctl.recorded,
+            vec![
+                "systemctl --user enable syncthing.service",
+                "systemctl --user start syncthing.service",
+            ]
+        );
+
+        let mut systemctl = Systemctl::new(Scope::User, true);
+        revert(
+            &setup(src),
+            &unit_dir,
+            &backup_dir,
+            &mut systemctl,
+            &mut batch,
+        )
+        .unwrap();
+        assert!(!unit_dir.join("syncthing.service").exists());
+        assert_eq!(
+            systemctl.recorded,
+            vec![
+                "systemctl --user stop syncthing.service",
+                "systemctl --user disable syncthing.service",
+                "systemctl --user daemon-reload",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_revert_restores_replaced_unit() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let src = test_dir.path().join("syncthing.service");
+        std::fs::write(&src, "[Service]\nExecStart=/usr/bin/syncthing\n").unwrap();
+        let unit_dir = test_dir.path().join("systemd/user");
+        let backup_dir = test_dir.path().join("backup");
+        std::fs::create_dir_all(&unit_dir).unwrap();
+        std::fs::write(
+            unit_dir.join("syncthing.service"),
+            "[Service]\nExecStart=old\n",
+        )
+        .unwrap();
+        let renderer = Renderer::new(&Host::current(), &Vars::new(), false);
+        let mut batch = PrivilegedBatch::new(Escalation::None);
+
+        let mut systemctl = Systemctl::new(Scope::User, true);
+        apply(
+            &setup(src.clone()),
+            &unit_dir,
+            Some(&backup_dir),
+            &renderer,
+            &mut systemctl,
+            &mut batch,
+        )
+        .unwrap();
+
+        let mut systemctl = Systemctl::new(Scope::User, true);
+        revert(
+            &setup(src),
+            &unit_dir,
+            &backup_dir,
+            &mut systemctl,
+            &mut batch,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(unit_dir.join("syncthing.service")).unwrap(),
+            "[Service]\nExecStart=old\n"
+        );
+        assert!(systemctl
+            .recorded
+            .contains(&"systemctl --user daemon-reload".to_string()));
+    }
+}
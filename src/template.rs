@@ -1,19 +1,45 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
 
-use tera::Context;
+use resolve_path::PathResolveExt;
+use tera::{Context, Tera};
 
-fn default_context() -> Context {
+use crate::{host::Host, secrets::Secrets, vars::Vars};
+
+mod functions;
+mod variables;
+
+fn default_context(host: &Host) -> Context {
     // add hostname, arch, os, etc
     let mut context = Context::new();
 
-    if let Ok(hostname) = hostname::get() {
-        context.insert("hostname", &hostname.to_string_lossy());
+    if let Some(hostname) = &host.hostname {
+        context.insert("hostname", hostname);
     }
 
-    context.insert("arch", std::env::consts::ARCH);
+    context.insert("arch", &host.arch);
 
     // OS
-    context.insert("os", std::env::consts::OS);
+    context.insert("os", &host.os);
+
+    context.insert("tags", &host.tags);
+
+    // Directories, mostly useful in destination paths
+    let dirs = [
+        ("home", dirs::home_dir()),
+        ("xdg_config_home", dirs::config_dir()),
+        ("xdg_data_home", dirs::data_dir()),
+        ("xdg_cache_home", dirs::cache_dir()),
+        ("xdg_state_home", dirs::state_dir()),
+    ];
+    for (name, dir) in dirs {
+        if let Some(dir) = dir {
+            context.insert(name, &dir.to_string_lossy());
+        }
+    }
 
     // Misc info
     context.insert("nproc", &num_cpus::get());
@@ -21,30 +47,606 @@ fn default_context() -> Context {
     context
 }
 
-pub fn render(template: &str, extra: &HashMap<String, String>) -> Result<String, tera::Error> {
-    let mut tera = tera::Tera::default();
-    let mut context = default_context();
+/// Renders the templates of a fixture with its variables and the spaceconf functions
+pub struct Renderer {
+    tera: Tera,
+    context: Context,
+    secrets: Secrets,
+    partials: BTreeMap<String, Partial>,
+    strict: bool,
+}
+
+/// What a shared partial reads from the context, and the templates it pulls in itself
+struct Partial {
+    variables: BTreeSet<String>,
+    included: BTreeSet<String>,
+}
+
+impl Renderer {
+    pub fn new(host: &Host, extra: &Vars, allow_commands: bool) -> Self {
+        let mut tera = Tera::default();
+        // config files are never HTML, even if they end in .html or .xml
+        tera.autoescape_on(vec![]);
+        functions::register(&mut tera, allow_commands);
+
+        let mut context = default_context(host);
+        if !extra.is_empty() {
+            context.extend(Context::from_serialize(extra).unwrap());
+        }
+
+        Self {
+            tera,
+            context,
+            secrets: Secrets::new(),
+            partials: BTreeMap::new(),
+            strict: false,
+        }
+    }
+
+    /// Secrets are only resolved when a template uses them, and take precedence over variables
+    pub fn with_secrets(mut self, secrets: &Secrets) -> Self {
+        self.secrets = secrets.clone();
+        self
+    }
+
+    /// In strict mode, a template fails if it references any variable that isn't provided,
+    /// even if it is only used in a condition
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Load the shared partials in `<repo>/templates` so they can be used with `include`,
+    /// `import` and `extends`. Partials are named by their path relative to the repo, e.g.
+    /// `{% include "templates/aliases.sh" %}`.
+    pub fn with_partials(mut self, repo_dir: &Path) -> Result<Self, tera::Error> {
+        let mut partials = Vec::new();
+        let templates_dir = repo_dir.join(PARTIALS_DIR);
+        if templates_dir.is_dir() {
+            find_partials(&templates_dir, &mut partials)
+                .map_err(|e| tera::Error::chain("Failed to read partials", e))?;
+        }
+
+        let partials = partials
+            .into_iter()
+            .map(|path| {
+                let name = path
+                    .strip_prefix(repo_dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                std::fs::read_to_string(&path)
+                    .map(|content| (name, content))
+                    .map_err(|e| tera::Error::chain(format!("Failed to read {:?}", path), e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (name, content) in &partials {
+            let partial = Partial {
+                variables: variables::used(name, content)?,
+                included: variables::included(name, content)?,
+            };
+            self.partials.insert(name.clone(), partial);
+        }
+        self.tera.add_raw_templates(partials)?;
+        Ok(self)
+    }
+
+    /// Render a template, `name` is used to identify it in errors
+    pub fn render(&self, name: &str, template: &str) -> Result<String, tera::Error> {
+        let mut tera = self.tera.clone();
+        tera.add_raw_template(name, template)?;
+        let context = self.context_for(name, template)?;
+        tera.render(name, &context)
+    }
+
+    /// Every variable the template may read, including through the partials it pulls in,
+    /// directly or through other partials
+    fn used(&self, name: &str, template: &str) -> Result<BTreeSet<String>, tera::Error> {
+        let mut used = variables::used(name, template)?;
+        let mut pending: Vec<_> = variables::included(name, template)?.into_iter().collect();
+        let mut seen = BTreeSet::new();
+        while let Some(included) = pending.pop() {
+            if !seen.insert(included.clone()) {
+                continue;
+            }
+            if let Some(partial) = self.partials.get(&included) {
+                used.extend(partial.variables.iter().cloned());
+                pending.extend(partial.included.iter().cloned());
+            }
+        }
+
+        Ok(used)
+    }
+
+    /// The context along with the secrets the template or its partials use
+    fn context_for(&self, name: &str, template: &str) -> Result<Cow<'_, Context>, tera::Error> {
+        if self.secrets.is_empty() {
+            return Ok(Cow::Borrowed(&self.context));
+        }
+
+        let mut context = self.context.clone();
+        for variable in &self.used(name, template)? {
+            if let Some(secret) = self.secrets.get(variable) {
+                let value = secret.resolve().map_err(|e| {
+                    tera::Error::chain(format!("Failed to resolve secret `{}`", variable), e)
+                })?;
+                context.insert(variable.as_str(), &value);
+            }
+        }
+
+        Ok(Cow::Owned(context))
+    }
+
+    /// Render a source file, reporting where it failed
+    pub fn render_file(&self, path: &Path, source: &str) -> Result<String, RenderError> {
+        let name = path.to_string_lossy();
+
+        if self.strict {
+            let provided = self.provided();
+            let missing: Vec<_> = variables::referenced(&name, source)
+                .map_err(|e| RenderError::new(&e, &name, source))?
+                .into_iter()
+                .filter(|variable| !provided.contains(variable))
+                .collect();
+
+            if let Some(variable) = missing.first() {
+                return Err(RenderError {
+                    template: name.to_string(),
+                    line: find_line(source, variable),
+                    variable: Some(variable.clone()),
+                    message: format!("Variable(s) not defined: {}", missing.join(", ")),
+                });
+            }
+        }
+
+        self.render(&name, source)
+            .map_err(|e| RenderError::new(&e, &name, source))
+    }
+
+    /// Names of the secrets that would be resolved to render the template
+    pub fn used_secrets(&self, name: &str, source: &str) -> Result<BTreeSet<String>, tera::Error> {
+        Ok(self
+            .used(name, source)?
+            .into_iter()
+            .filter(|variable| self.secrets.contains_key(variable))
+            .collect())
+    }
+
+    /// Variables the template reads from the context
+    pub fn referenced(&self, path: &Path, source: &str) -> Result<BTreeSet<String>, RenderError> {
+        let name = path.to_string_lossy();
+        variables::referenced(&name, source).map_err(|e| RenderError::new(&e, &name, source))
+    }
+
+    /// Names of all variables available to templates
+    pub fn provided(&self) -> BTreeSet<String> {
+        let mut provided = match self.context.clone().into_json() {
+            tera::Value::Object(map) => map.keys().cloned().collect(),
+            _ => BTreeSet::new(),
+        };
+        provided.extend(self.secrets.keys().cloned());
+
+        provided
+    }
+
+    /// Render a path from a fixture definition. Template expressions are rendered first, then
+    /// `$VAR` and `${VAR}` environment variables are expanded, then `~` is resolved.
+    pub fn render_path(&self, path: &Path) -> Result<PathBuf, tera::Error> {
+        let path = path.to_string_lossy();
+        let rendered = if path.contains("{{") || path.contains("{%") {
+            self.render(&path, &path)?
+        } else {
+            path.to_string()
+        };
+
+        let expanded = expand_env(&rendered)?;
+        Ok(PathBuf::from(expanded).resolve().to_path_buf())
+    }
+}
+
+/// A template that failed to render, with as much detail as could be recovered from Tera
+#[derive(Debug)]
+pub struct RenderError {
+    pub template: String,
+    pub line: Option<usize>,
+    pub variable: Option<String>,
+    /// Tera's error with its causes, shown unless a variable is missing
+    pub message: String,
+}
+
+impl RenderError {
+    fn new(error: &tera::Error, template: &str, source: &str) -> Self {
+        let chain = error_chain(error);
+        let variable = missing_variable(&chain);
+        let line = parse_error_line(&chain).or_else(|| {
+            variable
+                .as_deref()
+                .and_then(|variable| find_line(source, variable))
+        });
+
+        Self {
+            template: template.to_string(),
+            line,
+            variable,
+            message: chain,
+        }
+    }
+}
+
+impl std::fmt::Display for RenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.template)?;
+        if let Some(line) = self.line {
+            write!(f, "{}:", line)?;
+        }
+        match &self.variable {
+            Some(variable) => write!(f, " Variable `{}` is not defined", variable),
+            None => write!(f, " {}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+fn missing_variable(message: &str) -> Option<String> {
+    let (_, rest) = message.split_once("Variable `")?;
+    let (variable, _) = rest.split_once('`')?;
+    Some(variable.to_string())
+}
+
+/// Line number from a parse error, which looks like ` --> 3:15`
+fn parse_error_line(message: &str) -> Option<usize> {
+    let (_, rest) = message.split_once("--> ")?;
+    let (line, _) = rest.split_once(':')?;
+    line.parse().ok()
+}
+
+/// First line that uses the variable inside a tag
+fn find_line(source: &str, variable: &str) -> Option<usize> {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    source
+        .lines()
+        .position(|line| {
+            (line.contains("{{") || line.contains("{%"))
+                && line.match_indices(variable).any(|(index, _)| {
+                    let before = line[..index].chars().next_back();
+                    let after = line[index + variable.len()..].chars().next();
+                    !before.is_some_and(is_ident) && !after.is_some_and(is_ident)
+                })
+        })
+        .map(|index| index + 1)
+}
+
+/// Format an error along with all of its causes, as Tera's own messages only name the template
+pub fn error_chain(error: &tera::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        message += &format!(": {}", error);
+        source = error.source();
+    }
+
+    message
+}
+
+/// Expand `$VAR` and `${VAR}`, failing if a variable is not set
+fn expand_env(input: &str) -> Result<String, tera::Error> {
+    let mut output = String::new();
+    let mut rest = input;
+
+    while let Some(index) = rest.find('$') {
+        output += &rest[..index];
+        rest = &rest[index + 1..];
+
+        let (name, remaining) = if let Some(braced) = rest.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => return Err(tera::Error::msg(format!("Unclosed `${{` in {:?}", input))),
+            }
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+
+        if name.is_empty() {
+            output.push('$');
+            continue;
+        }
+
+        let value = std::env::var(name)
+            .map_err(|_| tera::Error::msg(format!("Environment variable `{}` is not set", name)))?;
+        output += &value;
+        rest = remaining;
+    }
+
+    output += rest;
+    Ok(output)
+}
+
+const PARTIALS_DIR: &str = "templates";
 
-    if !extra.is_empty() {
-        context.extend(Context::from_serialize(extra).unwrap());
+fn find_partials(dir: &Path, partials: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_partials(&path, partials)?;
+        } else {
+            partials.push(path);
+        }
     }
 
-    tera.render_str(template, &context)
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use serde_json::json;
 
     use super::*;
+    use crate::secrets::Secret;
 
     #[test]
     fn test_render() {
         let template = "Hello, {{ name }}!";
-        let mut extra = HashMap::new();
-        extra.insert("name".to_string(), "world".to_string());
+        let mut extra = Vars::new();
+        extra.insert("name".to_string(), json!("world"));
 
-        let result = render(template, &extra).unwrap();
+        let result = Renderer::new(&Host::current(), &extra, false)
+            .render("test", template)
+            .unwrap();
         assert_eq!(result, "Hello, world!");
     }
+
+    #[test]
+    fn test_render_secrets() {
+        let mut extra = Vars::new();
+        extra.insert("API_KEY".to_string(), json!("placeholder"));
+        let secrets = Secrets::from_iter(vec![
+            ("API_KEY".into(), "12345abcde".into()),
+            (
+                "UNUSED".into(),
+                Secret::Command {
+                    command: "exit 1".into(),
+                },
+            ),
+        ]);
+
+        let result = Renderer::new(&Host::current(), &extra, false)
+            .with_secrets(&secrets)
+            .render("test", "--api-key {{ API_KEY }}")
+            .unwrap();
+        assert_eq!(result, "--api-key 12345abcde");
+    }
+
+    #[test]
+    fn test_used_secrets() {
+        let secrets = Secrets::from_iter(vec![
+            ("API_KEY".into(), "12345abcde".into()),
+            ("GITHUB_TOKEN".into(), "ghp_12345".into()),
+        ]);
+
+        let used = Renderer::new(&Host::current(), &Vars::new(), false)
+            .with_secrets(&secrets)
+            .used_secrets("test", "{{ API_KEY }} {{ user }}")
+            .unwrap();
+        assert_eq!(used.into_iter().collect::<Vec<_>>(), vec!["API_KEY"]);
+    }
+
+    #[test]
+    fn test_render_failed_secret() {
+        let secrets = Secrets::from_iter(vec![(
+            "API_KEY".into(),
+            Secret::Command {
+                command: "exit 1".into(),
+            },
+        )]);
+
+        let error = Renderer::new(&Host::current(), &Vars::new(), false)
+            .with_secrets(&secrets)
+            .render("test", "--api-key {{ API_KEY }}")
+            .unwrap_err();
+        assert!(error_chain(&error).contains("Failed to resolve secret `API_KEY`"));
+    }
+
+    #[test]
+    fn test_render_for_host() {
+        let template = "{{ hostname }} {{ os }}-{{ arch }}";
+        let host = Host::current().with_overrides(
+            Some("desktop".into()),
+            Some("freebsd".into()),
+            Some("aarch64".into()),
+        );
+
+        let result = Renderer::new(&host, &Vars::new(), false)
+            .render("test", template)
+            .unwrap();
+        assert_eq!(result, "desktop freebsd-aarch64");
+    }
+
+    #[test]
+    fn test_render_nested() {
+        let template = "{{ colors.bg }} {% for font in fonts %}{{ font }} {% endfor %}";
+        let extra = json!({
+            "colors": { "bg": "#000000" },
+            "fonts": ["Iosevka", "Fira Code"]
+        });
+
+        let result = Renderer::new(&Host::current(), extra.as_object().unwrap(), false)
+            .render("test", template)
+            .unwrap();
+        assert_eq!(result, "#000000 Iosevka Fira Code ");
+    }
+
+    #[test]
+    fn test_render_file_missing_variable() {
+        let renderer = Renderer::new(&Host::current(), &Vars::new(), false);
+        let source = "# config\nuser = {{ user }}\nkey = {{ API_KEY }}\n";
+
+        let error = renderer
+            .render_file(Path::new("/dotfiles/app/config"), source)
+            .unwrap_err();
+        assert_eq!(error.variable.as_deref(), Some("user"));
+        assert_eq!(error.line, Some(2));
+        assert!(error.to_string().starts_with("/dotfiles/app/config:2: "));
+    }
+
+    #[test]
+    fn test_render_file_parse_error() {
+        let renderer = Renderer::new(&Host::current(), &Vars::new(), false);
+        let source = "# config\n\nuser = {{ user ) }}\n";
+
+        let error = renderer
+            .render_file(Path::new("config"), source)
+            .unwrap_err();
+        assert_eq!(error.line, Some(3), "{}", error);
+        assert_eq!(error.variable, None);
+    }
+
+    #[test]
+    fn test_render_file_strict() {
+        let extra = json!({ "user": "me" });
+        let source = "{% if theme %}{{ theme }}{% endif %}{{ user }}";
+
+        let renderer = Renderer::new(&Host::current(), extra.as_object().unwrap(), false);
+        assert_eq!(
+            renderer.render_file(Path::new("config"), source).unwrap(),
+            "me"
+        );
+
+        let error = renderer
+            .strict(true)
+            .render_file(Path::new("config"), source)
+            .unwrap_err();
+        assert_eq!(error.variable.as_deref(), Some("theme"));
+        assert_eq!(error.line, Some(1));
+    }
+
+    #[test]
+    fn test_render_path() {
+        let home = dirs::home_dir().unwrap();
+        let extra = json!({ "app": "foo" });
+        let renderer = Renderer::new(&Host::current(), extra.as_object().unwrap(), false);
+
+        assert_eq!(
+            renderer
+                .render_path(Path::new("{{ home }}/.config/{{ app }}/config"))
+                .unwrap(),
+            home.join(".config/foo/config")
+        );
+        assert_eq!(
+            renderer.render_path(Path::new("$HOME/${HOME}")).unwrap(),
+            PathBuf::from(format!("{0}/{0}", home.display()))
+        );
+        assert_eq!(
+            renderer.render_path(Path::new("~/{{ app }}")).unwrap(),
+            home.join("foo")
+        );
+    }
+
+    #[test]
+    fn test_render_path_unresolvable() {
+        let renderer = Renderer::new(&Host::current(), &Vars::new(), false);
+
+        let error = renderer
+            .render_path(Path::new("{{ missing }}/config"))
+            .unwrap_err();
+        assert!(error_chain(&error).contains("`missing`"));
+
+        let error = renderer
+            .render_path(Path::new("$SPACECONF_UNSET_VARIABLE/config"))
+            .unwrap_err();
+        assert!(error_chain(&error).contains("`SPACECONF_UNSET_VARIABLE`"));
+    }
+
+    #[test]
+    fn test_render_partials() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let templates_dir = test_dir.path().join("templates");
+        std::fs::create_dir_all(templates_dir.join("shell")).unwrap();
+        std::fs::write(
+            templates_dir.join("shell/aliases.sh"),
+            "alias vim={{ editor }}\n",
+        )
+        .unwrap();
+        std::fs::write(
+            templates_dir.join("colors.tera"),
+            "{% macro color(name) %}#{{ name }}{% endmacro color %}",
+        )
+        .unwrap();
+
+        let extra = json!({ "editor": "nvim" });
+        let renderer = Renderer::new(&Host::current(), extra.as_object().unwrap(), false)
+            .with_partials(test_dir.path())
+            .unwrap();
+
+        let template = r#"{% import "templates/colors.tera" as colors -%}
+{% include "templates/shell/aliases.sh" -%}
+bg={{ colors::color(name="000000") }}"#;
+        let result = renderer.render(".bashrc", template).unwrap();
+        assert_eq!(result, "alias vim=nvim\nbg=#000000");
+    }
+
+    #[test]
+    fn test_render_resolves_included_partial_secrets_only() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let templates_dir = test_dir.path().join("templates");
+        std::fs::create_dir(&templates_dir).unwrap();
+        std::fs::write(templates_dir.join("netrc"), "password {{ TOKEN }}").unwrap();
+        std::fs::write(
+            templates_dir.join("auth"),
+            r#"{% include "templates/netrc" %}"#,
+        )
+        .unwrap();
+        std::fs::write(templates_dir.join("wifi"), "psk={{ PSK }}").unwrap();
+
+        let secrets = Secrets::from_iter(vec![
+            ("TOKEN".into(), "12345abcde".into()),
+            (
+                "PSK".into(),
+                Secret::Command {
+                    command: "exit 1".into(),
+                },
+            ),
+        ]);
+        let renderer = Renderer::new(&Host::current(), &Vars::new(), false)
+            .with_secrets(&secrets)
+            .with_partials(test_dir.path())
+            .unwrap();
+
+        // the wifi partial isn't included, so its secret command never runs
+        let result = renderer
+            .render(".netrc", r#"{% include "templates/auth" %}"#)
+            .unwrap();
+        assert_eq!(result, "password 12345abcde");
+        assert_eq!(
+            renderer
+                .used_secrets(".netrc", r#"{% include "templates/auth" %}"#)
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["TOKEN"]
+        );
+    }
+
+    #[test]
+    fn test_render_partial_error() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let templates_dir = test_dir.path().join("templates");
+        std::fs::create_dir(&templates_dir).unwrap();
+        std::fs::write(templates_dir.join("aliases.sh"), "alias vim={{ editor }}").unwrap();
+
+        let renderer = Renderer::new(&Host::current(), &Vars::new(), false)
+            .with_partials(test_dir.path())
+            .unwrap();
+
+        let error = renderer
+            .render(".bashrc", r#"{% include "templates/aliases.sh" %}"#)
+            .unwrap_err();
+        let source = std::error::Error::source(&error).unwrap().to_string();
+        assert!(source.contains("templates/aliases.sh"), "{}", source);
+    }
 }
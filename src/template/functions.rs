@@ -0,0 +1,218 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use resolve_path::PathResolveExt;
+use tera::{Error, Result, Tera, Value};
+
+/// Register the spaceconf functions and filters available to every template.
+///
+/// `command_output` is always registered, but refuses to run unless `allow_commands` is set
+/// so templates get a helpful error rather than an unknown function.
+pub fn register(tera: &mut Tera, allow_commands: bool) {
+    tera.register_function("env", env);
+    tera.register_function("file_exists", file_exists);
+    tera.register_function("lookup_path", lookup_path);
+    tera.register_function("include_file", include_file);
+    tera.register_function("command_output", move |args: &HashMap<String, Value>| {
+        command_output(args, allow_commands)
+    });
+
+    tera.register_filter("to_json", to_json);
+    tera.register_filter("to_toml", to_toml);
+}
+
+fn string_arg<'a>(function: &str, args: &'a HashMap<String, Value>, name: &str) -> Result<&'a str> {
+    match args.get(name) {
+        Some(Value::String(value)) => Ok(value),
+        Some(_) => Err(Error::msg(format!(
+            "`{}`: argument `{}` must be a string",
+            function, name
+        ))),
+        None => Err(Error::msg(format!(
+            "`{}`: missing argument `{}`",
+            function, name
+        ))),
+    }
+}
+
+fn path_arg(function: &str, args: &HashMap<String, Value>) -> Result<PathBuf> {
+    let path = string_arg(function, args, "path")?;
+    Ok(PathBuf::from(path).resolve().to_path_buf())
+}
+
+/// `env(name="HOME", default="")`
+fn env(args: &HashMap<String, Value>) -> Result<Value> {
+    let name = string_arg("env", args, "name")?;
+    match (std::env::var(name), args.get("default")) {
+        (Ok(value), _) => Ok(Value::String(value)),
+        (Err(_), Some(default)) => Ok(default.clone()),
+        (Err(_), None) => Err(Error::msg(format!(
+            "`env`: environment variable `{}` is not set and no default was given",
+            name
+        ))),
+    }
+}
+
+/// `file_exists(path="~/.local/bin/starship")`
+fn file_exists(args: &HashMap<String, Value>) -> Result<Value> {
+    Ok(Value::Bool(path_arg("file_exists", args)?.exists()))
+}
+
+/// `lookup_path(name="nvim")`, returns the full path or an empty string if not found
+fn lookup_path(args: &HashMap<String, Value>) -> Result<Value> {
+    let name = string_arg("lookup_path", args, "name")?;
+    let found = std::env::var_os("PATH")
+        .map(|path| {
+            std::env::split_paths(&path)
+                .map(|dir| dir.join(name))
+                .find(|candidate| candidate.is_file())
+        })
+        .unwrap_or_default();
+
+    Ok(Value::String(
+        found
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    ))
+}
+
+/// `include_file(path="~/.config/colors.conf")`, inserts the file's contents verbatim
+fn include_file(args: &HashMap<String, Value>) -> Result<Value> {
+    let path = path_arg("include_file", args)?;
+    std::fs::read_to_string(&path)
+        .map(Value::String)
+        .map_err(|e| Error::chain(format!("`include_file`: failed to read {:?}", path), e))
+}
+
+/// `command_output(command="uname -r")`, runs the command with `sh -c` and returns its
+/// trimmed stdout. Only available in fixtures with `"allow_commands": true`.
+fn command_output(args: &HashMap<String, Value>, allowed: bool) -> Result<Value> {
+    let command = string_arg("command_output", args, "command")?;
+    if !allowed {
+        return Err(Error::msg(format!(
+            "`command_output`: running `{}` is not allowed, \
+             set \"allow_commands\": true in the fixture to enable it",
+            command
+        )));
+    }
+
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|e| Error::chain(format!("`command_output`: failed to run `{}`", command), e))?;
+
+    if !output.status.success() {
+        return Err(Error::msg(format!(
+            "`command_output`: `{}` exited with {}",
+            command, output.status
+        )));
+    }
+
+    Ok(Value::String(
+        String::from_utf8_lossy(&output.stdout)
+            .trim_end()
+            .to_string(),
+    ))
+}
+
+/// `{{ value | to_json(pretty=true) }}`
+fn to_json(value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
+    let pretty = args.get("pretty").and_then(Value::as_bool).unwrap_or(false);
+
+    let json = if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    };
+
+    json.map(Value::String)
+        .map_err(|e| Error::chain("`to_json`: failed to serialize value", e))
+}
+
+/// `{{ value | to_toml }}`, the value must be an object
+fn to_toml(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    toml::to_string(value)
+        .map(|toml| Value::String(toml.trim_end().to_string()))
+        .map_err(|e| Error::chain("`to_toml`: failed to serialize value", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tera::Context;
+
+    use super::*;
+
+    fn render(template: &str, allow_commands: bool) -> Result<String> {
+        let mut tera = Tera::default();
+        register(&mut tera, allow_commands);
+
+        let mut context = Context::new();
+        context.insert("colors", &json!({ "fg": "#ffffff", "bg": "#000000" }));
+        tera.render_str(template, &context)
+    }
+
+    #[test]
+    fn test_env() {
+        let path = std::env::var("PATH").unwrap();
+
+        assert_eq!(render("{{ env(name=\"PATH\") }}", false).unwrap(), path);
+        assert_eq!(
+            render(
+                "{{ env(name=\"SPACECONF_UNSET_VARIABLE\", default=\"fallback\") }}",
+                false
+            )
+            .unwrap(),
+            "fallback"
+        );
+        assert!(render("{{ env(name=\"SPACECONF_UNSET_VARIABLE\") }}", false).is_err());
+    }
+
+    #[test]
+    fn test_file_exists_and_include_file() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let path = test_dir.path().join("included.conf");
+        std::fs::write(&path, "included").unwrap();
+
+        let template = format!(
+            "{{% if file_exists(path=\"{0}\") %}}{{{{ include_file(path=\"{0}\") }}}}{{% endif %}}",
+            path.display()
+        );
+        assert_eq!(render(&template, false).unwrap(), "included");
+
+        let missing = test_dir.path().join("missing.conf");
+        let template = format!("{{{{ file_exists(path=\"{}\") }}}}", missing.display());
+        assert_eq!(render(&template, false).unwrap(), "false");
+    }
+
+    #[test]
+    fn test_lookup_path() {
+        assert!(render("{{ lookup_path(name=\"sh\") }}", false)
+            .unwrap()
+            .ends_with("/sh"));
+        assert_eq!(
+            render("{{ lookup_path(name=\"spaceconf-nonexistent\") }}", false).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_command_output() {
+        let template = "{{ command_output(command=\"echo hello\") }}";
+
+        assert_eq!(render(template, true).unwrap(), "hello");
+        assert!(render(template, false).is_err());
+    }
+
+    #[test]
+    fn test_to_json_and_to_toml() {
+        assert_eq!(
+            render("{{ colors | to_json | safe }}", false).unwrap(),
+            r##"{"bg":"#000000","fg":"#ffffff"}"##
+        );
+        assert_eq!(
+            render("{{ colors | to_toml | safe }}", false).unwrap(),
+            "bg = \"#000000\"\nfg = \"#ffffff\""
+        );
+    }
+}
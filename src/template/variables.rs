@@ -0,0 +1,232 @@
+use std::collections::BTreeSet;
+
+use tera::{
+    ast::{Expr, ExprVal, Node},
+    Template,
+};
+
+/// Variables the template reads from the context
+///
+/// Names bound by the template itself (loop variables, `set` and macro arguments) are left
+/// out, as are variables the template checks with an `is defined` test or gives a `default`.
+pub fn referenced(name: &str, source: &str) -> tera::Result<BTreeSet<String>> {
+    let visitor = visit(name, source)?;
+
+    Ok(visitor
+        .variables
+        .into_iter()
+        .filter(|variable| {
+            !visitor.locals.contains(variable) && !visitor.guarded.contains(variable)
+        })
+        .collect())
+}
+
+/// Every context variable the template may read, including guarded ones
+pub fn used(name: &str, source: &str) -> tera::Result<BTreeSet<String>> {
+    let visitor = visit(name, source)?;
+
+    Ok(visitor
+        .variables
+        .into_iter()
+        .chain(visitor.guarded)
+        .filter(|variable| !visitor.locals.contains(variable))
+        .collect())
+}
+
+/// Templates the template includes, imports macros from or extends
+pub fn included(name: &str, source: &str) -> tera::Result<BTreeSet<String>> {
+    Ok(visit(name, source)?.included)
+}
+
+fn visit(name: &str, source: &str) -> tera::Result<Visitor> {
+    let template = Template::new(name, None, source)?;
+
+    let mut visitor = Visitor::default();
+    visitor.nodes(&template.ast);
+    for definition in template.macros.values() {
+        visitor.locals.extend(definition.args.keys().cloned());
+        for default in definition.args.values().flatten() {
+            visitor.expr(default);
+        }
+        visitor.nodes(&definition.body);
+    }
+
+    Ok(visitor)
+}
+
+#[derive(Default)]
+struct Visitor {
+    variables: BTreeSet<String>,
+    locals: BTreeSet<String>,
+    guarded: BTreeSet<String>,
+    included: BTreeSet<String>,
+}
+
+impl Visitor {
+    fn nodes(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            self.node(node);
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        match node {
+            Node::VariableBlock(_, expr) => self.expr(expr),
+            Node::MacroDefinition(_, definition, _) => {
+                self.locals.extend(definition.args.keys().cloned());
+                self.nodes(&definition.body);
+            }
+            Node::Set(_, set) => {
+                self.locals.insert(set.key.clone());
+                self.expr(&set.value);
+            }
+            Node::FilterSection(_, section, _) => {
+                section.filter.args.values().for_each(|arg| self.expr(arg));
+                self.nodes(&section.body);
+            }
+            Node::Block(_, block, _) => self.nodes(&block.body),
+            Node::Forloop(_, forloop, _) => {
+                self.locals.extend(forloop.key.clone());
+                self.locals.insert(forloop.value.clone());
+                self.expr(&forloop.container);
+                self.nodes(&forloop.body);
+                if let Some(body) = &forloop.empty_body {
+                    self.nodes(body);
+                }
+            }
+            Node::If(condition, _) => {
+                for (_, expr, body) in &condition.conditions {
+                    self.expr(expr);
+                    self.nodes(body);
+                }
+                if let Some((_, body)) = &condition.otherwise {
+                    self.nodes(body);
+                }
+            }
+            Node::Extends(_, name) | Node::ImportMacro(_, name, _) => {
+                self.included.insert(name.clone());
+            }
+            Node::Include(_, names, _) => self.included.extend(names.iter().cloned()),
+            Node::Super
+            | Node::Text(_)
+            | Node::Raw(..)
+            | Node::Break(_)
+            | Node::Continue(_)
+            | Node::Comment(..) => {}
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        for filter in &expr.filters {
+            filter.args.values().for_each(|arg| self.expr(arg));
+        }
+
+        match &expr.val {
+            ExprVal::Ident(ident) if expr.has_default_filter() => {
+                self.guarded.insert(root(ident).to_string());
+            }
+            val => self.val(val),
+        }
+    }
+
+    fn val(&mut self, val: &ExprVal) {
+        match val {
+            ExprVal::Ident(ident) => self.ident(ident),
+            ExprVal::Math(math) => {
+                self.expr(&math.lhs);
+                self.expr(&math.rhs);
+            }
+            ExprVal::Logic(logic) => {
+                self.expr(&logic.lhs);
+                self.expr(&logic.rhs);
+            }
+            ExprVal::Test(test) => {
+                if matches!(test.name.as_str(), "defined" | "undefined") {
+                    self.guarded.insert(root(&test.ident).to_string());
+                } else {
+                    self.ident(&test.ident);
+                }
+                test.args.iter().for_each(|arg| self.expr(arg));
+            }
+            ExprVal::MacroCall(call) => call.args.values().for_each(|arg| self.expr(arg)),
+            ExprVal::FunctionCall(call) => call.args.values().for_each(|arg| self.expr(arg)),
+            ExprVal::Array(values) => values.iter().for_each(|value| self.expr(value)),
+            ExprVal::StringConcat(concat) => concat.values.iter().for_each(|value| self.val(value)),
+            ExprVal::In(expr) => {
+                self.expr(&expr.lhs);
+                self.expr(&expr.rhs);
+            }
+            ExprVal::String(_) | ExprVal::Int(_) | ExprVal::Float(_) | ExprVal::Bool(_) => {}
+        }
+    }
+
+    fn ident(&mut self, ident: &str) {
+        let root = root(ident);
+        if !matches!(root, "loop" | "__tera_context") {
+            self.variables.insert(root.to_string());
+        }
+    }
+}
+
+/// The top-level variable of an identifier, e.g. `colors` for `colors.bg`
+fn root(ident: &str) -> &str {
+    ident.split(['.', '[']).next().unwrap_or(ident)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_referenced() {
+        let source = r#"
+{%- set greeting = "Hello" -%}
+{{ greeting }}, {{ user.name | upper }}!
+{% for font in fonts %}{{ loop.index }}: {{ font }}{% endfor %}
+{% if theme is defined %}{{ theme }}{% endif %}
+{{ editor | default(value="vim") }}
+{{ env(name=shell_var) }}
+{% if os == "linux" and colors["bg"] %}{% endif %}
+"#;
+
+        let variables = referenced("test", source).unwrap();
+        assert_eq!(
+            variables.into_iter().collect::<Vec<_>>(),
+            vec!["colors", "fonts", "os", "shell_var", "user"]
+        );
+
+        let variables = used("test", source).unwrap();
+        assert_eq!(
+            variables.into_iter().collect::<Vec<_>>(),
+            vec![
+                "colors",
+                "editor",
+                "fonts",
+                "os",
+                "shell_var",
+                "theme",
+                "user"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_included() {
+        let source = r#"{% extends "templates/base.conf" %}
+{% import "templates/colors.tera" as colors %}
+{% block body %}{% include ["templates/local.sh", "templates/default.sh"] ignore missing %}{% endblock body %}"#;
+
+        assert_eq!(
+            included("test", source)
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![
+                "templates/base.conf",
+                "templates/colors.tera",
+                "templates/default.sh",
+                "templates/local.sh"
+            ]
+        );
+    }
+}
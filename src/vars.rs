@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use log::error;
+use serde_json::Value;
+
+use crate::host::Host;
+
+/// Non-secret template variables, which may contain nested objects and lists
+pub type Vars = serde_json::Map<String, Value>;
+
+/// Variables shared by every fixture in the repository
+///
+/// - `<repo>/vars.json` or `<repo>/vars.toml` applies to every machine
+/// - `<repo>/vars/<name>.json` or `<repo>/vars/<name>.toml` applies to machines whose OS,
+///   tag or hostname is `<name>`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RepoVars {
+    pub global: Vars,
+    pub overlays: HashMap<String, Vars>,
+}
+
+impl RepoVars {
+    pub fn load(repo_dir: &Path) -> std::io::Result<Self> {
+        let mut global = Vars::new();
+        for file in ["vars.json", "vars.toml"] {
+            let path = repo_dir.join(file);
+            if path.is_file() {
+                merge(&mut global, read_vars_file(&path)?);
+            }
+        }
+
+        let mut overlays = HashMap::new();
+        let overlay_dir = repo_dir.join("vars");
+        if overlay_dir.is_dir() {
+            for entry in std::fs::read_dir(&overlay_dir)? {
+                let path = entry?.path();
+                if !matches!(vars_format(&path), Some("json" | "toml")) {
+                    continue;
+                }
+
+                let name = path.file_stem().unwrap().to_string_lossy().to_string();
+                let overlay = overlays.entry(name).or_insert_with(Vars::new);
+                merge(overlay, read_vars_file(&path)?);
+            }
+        }
+
+        Ok(Self { global, overlays })
+    }
+
+    /// Resolve the variables for a fixture on the given host.
+    ///
+    /// From lowest to highest precedence: the global variables, the fixture's own `vars`,
+    /// then the overlays for the host's OS, each of its tags, and finally its hostname.
+    pub fn resolve(&self, fixture_vars: &Vars, host: &Host) -> Vars {
+        let mut vars = self.global.clone();
+        merge(&mut vars, fixture_vars.clone());
+
+        let names = std::iter::once(host.os.as_str())
+            .chain(host.tags.iter().map(String::as_str))
+            .chain(host.short_hostname());
+        for name in names {
+            if let Some(overlay) = self.overlays.get(name) {
+                merge(&mut vars, overlay.clone());
+            }
+        }
+
+        vars
+    }
+}
+
+/// Deep merge `overlay` into `base`. Objects are merged key by key, anything else
+/// (including lists) is replaced.
+pub fn merge(base: &mut Vars, overlay: Vars) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(base)), Value::Object(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn vars_format(path: &Path) -> Option<&str> {
+    path.extension().and_then(|extension| extension.to_str())
+}
+
+fn read_vars_file(path: &PathBuf) -> std::io::Result<Vars> {
+    let content = std::fs::read_to_string(path)
+        .inspect_err(|_| error!("failed to read vars file: {}", &path.to_string_lossy()))?;
+
+    let vars = match vars_format(path) {
+        Some("toml") => toml::from_str(&content).map_err(|e| e.to_string()),
+        _ => serde_json::from_str(&content).map_err(|e| e.to_string()),
+    };
+
+    vars.map_err(|e| {
+        error!("invalid vars file: {}", &path.to_string_lossy());
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn vars(value: Value) -> Vars {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_merge_nested() {
+        let mut base = vars(json!({
+            "font": "Iosevka",
+            "colors": { "fg": "#ffffff", "bg": "#000000" },
+            "editors": ["nvim", "helix"]
+        }));
+        merge(
+            &mut base,
+            vars(json!({
+                "colors": { "bg": "#1e1e2e" },
+                "editors": ["vim"]
+            })),
+        );
+
+        assert_eq!(
+            Value::Object(base),
+            json!({
+                "font": "Iosevka",
+                "colors": { "fg": "#ffffff", "bg": "#1e1e2e" },
+                "editors": ["vim"]
+            })
+        );
+    }
+
+    #[test]
+    fn test_load_and_resolve() {
+        let test_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+
+        std::fs::write(
+            test_dir.path().join("vars.toml"),
+            r##"
+email = "me@example.com"
+font = "Iosevka"
+
+[colors]
+fg = "#ffffff"
+bg = "#000000"
+"##,
+        )
+        .unwrap();
+        std::fs::create_dir(test_dir.path().join("vars")).unwrap();
+        std::fs::write(
+            test_dir.path().join("vars/linux.json"),
+            r##"{"colors": {"bg": "#111111"}}"##,
+        )
+        .unwrap();
+        std::fs::write(
+            test_dir.path().join("vars/desktop.toml"),
+            "[colors]\nbg = \"#222222\"\n",
+        )
+        .unwrap();
+
+        let repo_vars = RepoVars::load(test_dir.path()).unwrap();
+        let fixture_vars = vars(json!({ "font": "Fira Code", "colors": { "bg": "#333333" } }));
+
+        let host = Host::current().with_overrides(None, Some("linux".into()), None);
+        assert_eq!(
+            Value::Object(repo_vars.resolve(&fixture_vars, &host)),
+            json!({
+                "email": "me@example.com",
+                "font": "Fira Code",
+                "colors": { "fg": "#ffffff", "bg": "#111111" }
+            })
+        );
+
+        let host = host.with_overrides(Some("desktop".into()), None, None);
+        assert_eq!(
+            repo_vars.resolve(&fixture_vars, &host)["colors"]["bg"],
+            json!("#222222")
+        );
+    }
+}